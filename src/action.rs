@@ -0,0 +1,109 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use log::warn;
+use serde::Deserialize;
+
+use crate::config::ActionConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PowerAction {
+    Suspend,
+    Hibernate,
+    HybridSleep,
+    PowerOff,
+    /// Only run `command`, without asking logind.
+    Command,
+}
+
+impl PowerAction {
+    /// Method of `org.freedesktop.login1.Manager` performing the action.
+    fn logind_method(self) -> Option<&'static str> {
+        match self {
+            PowerAction::Suspend => Some("Suspend"),
+            PowerAction::Hibernate => Some("Hibernate"),
+            PowerAction::HybridSleep => Some("HybridSleep"),
+            PowerAction::PowerOff => Some("PowerOff"),
+            PowerAction::Command => None,
+        }
+    }
+}
+
+impl Display for PowerAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            PowerAction::Suspend => "suspend",
+            PowerAction::Hibernate => "hibernate",
+            PowerAction::HybridSleep => "hybrid sleep",
+            PowerAction::PowerOff => "power off",
+            PowerAction::Command => "the power command",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+fn call_logind(method: &str) -> zbus::Result<()> {
+    let connection = zbus::blocking::Connection::system()?;
+    // The argument disables polkit's interactive authentication prompt.
+    connection.call_method(
+        Some("org.freedesktop.login1"),
+        "/org/freedesktop/login1",
+        Some("org.freedesktop.login1.Manager"),
+        method,
+        &(false,),
+    )?;
+    Ok(())
+}
+
+fn run_command(command: &[String]) -> Result<(), Box<dyn Error>> {
+    let (program, args) = command.split_first().ok_or("no command configured")?;
+    let status = process::Command::new(program).args(args).status()?;
+    if !status.success() {
+        return Err(format!("{:?} exited with {}", command, status).into());
+    }
+    Ok(())
+}
+
+/// Performs the configured action through logind, falling back to
+/// `config.command` when the D-Bus call fails.
+pub fn execute(config: &ActionConfig) -> Result<(), Box<dyn Error>> {
+    if let Some(method) = config.action.logind_method() {
+        match call_logind(method) {
+            Ok(()) => return Ok(()),
+            Err(e) if !config.command.is_empty() => {
+                warn!("logind {} failed, running fallback command: {}", method, e)
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    run_command(&config.command)
+}
+
+/// A pending action, announced by an alert the user can cancel.
+pub struct Countdown {
+    pub deadline: Instant,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Countdown {
+    pub fn start(config: &ActionConfig, now: Instant) -> Self {
+        Countdown {
+            deadline: now + config.countdown,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Flag handed to the countdown alert, set when the user cancels.
+    pub fn cancel_flag(&self) -> Arc<AtomicBool> {
+        self.cancelled.clone()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
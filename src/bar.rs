@@ -0,0 +1,133 @@
+use std::io::{self, Write};
+use std::sync::mpsc::Sender;
+use std::time::Instant;
+
+use log::info;
+use serde_json::json;
+
+use crate::cli::BarProtocol;
+use crate::config::{self, Config, Threshold, Urgency};
+use crate::events::Event;
+use crate::monitor;
+use crate::notification;
+use crate::publisher::Publisher;
+use crate::source::BatteryStatus;
+
+/// Prints a status bar line for the combined battery to stdout whenever it
+/// changes. Stops the main loop once stdout is closed, as when the bar is
+/// reloaded.
+pub struct Bar<'a> {
+    config: &'a Config,
+    protocol: BarProtocol,
+    last: Option<String>,
+    stop: Option<Sender<Event>>,
+}
+
+impl<'a> Bar<'a> {
+    pub fn new(config: &'a Config, protocol: BarProtocol, stop: Sender<Event>) -> Self {
+        let mut bar = Bar {
+            config,
+            protocol,
+            last: None,
+            stop: Some(stop),
+        };
+        if protocol == BarProtocol::I3bar {
+            // The header, then an endless array of status lines.
+            bar.write(&format!("{}\n[", json!({ "version": 1 })));
+        }
+        bar
+    }
+
+    fn write(&mut self, line: &str) {
+        if self.stop.is_none() {
+            return;
+        }
+        if let Err(e) = writeln!(io::stdout().lock(), "{}", line) {
+            info!("stdout closed, exiting: {}", e);
+            if let Some(stop) = self.stop.take() {
+                let _ = stop.send(Event::Stop);
+            }
+        }
+    }
+
+    /// The most severe threshold crossed while discharging.
+    fn threshold(&self, system: &BatteryStatus) -> Option<&'a Threshold> {
+        if !monitor::is_discharging(system.state) {
+            return None;
+        }
+        self.config
+            .thresholds
+            .iter()
+            .filter(|t| t.enabled)
+            .rfind(|t| system.charge <= t.level())
+    }
+
+    fn icon(&self, system: &BatteryStatus, charging: bool) -> &'a str {
+        let bar = &self.config.bar;
+        let icons = if charging {
+            &bar.charging_icons
+        } else {
+            &bar.icons
+        };
+        let band = (system.charge.clamp(0.0, 1.0) * icons.len() as f32) as usize;
+        &icons[band.min(icons.len() - 1)]
+    }
+
+    fn line(&self, system: &BatteryStatus) -> String {
+        let bar = &self.config.bar;
+        let charging = !monitor::is_discharging(system.state);
+        let mut vars = notification::vars(system, false);
+        vars.push(("icon", self.icon(system, charging).to_string()));
+        let format = match &bar.format_charging {
+            Some(format) if charging => format,
+            _ => &bar.format,
+        };
+        let text = config::render(format, &vars);
+        let tooltip = config::render(&bar.tooltip, &vars);
+        let threshold = self.threshold(system);
+        let state = system.state.to_string();
+        match self.protocol {
+            BarProtocol::Plain => text,
+            BarProtocol::Waybar => {
+                // waybar applies each class to the module for CSS styling.
+                let mut class = vec![state.clone()];
+                class.extend(threshold.map(|t| t.name.clone()));
+                json!({
+                    "text": text,
+                    "alt": state,
+                    "tooltip": tooltip,
+                    "class": class,
+                    "percentage": (system.charge * 100.0).round() as u32,
+                })
+                .to_string()
+            }
+            BarProtocol::I3bar => {
+                let urgent = threshold.is_some_and(|t| t.urgency == Urgency::Critical);
+                let block = json!({
+                    "name": "battery",
+                    "instance": state,
+                    "full_text": text,
+                    "urgent": urgent,
+                });
+                format!("{},", json!([block]))
+            }
+        }
+    }
+}
+
+impl Publisher for Bar<'_> {
+    fn publish(&mut self, _: &[&BatteryStatus], system: Option<&BatteryStatus>, _: Instant) {
+        let line = match system {
+            Some(system) => self.line(system),
+            None => match self.protocol {
+                BarProtocol::Plain => "no battery".to_string(),
+                BarProtocol::Waybar => json!({ "text": "", "class": ["none"] }).to_string(),
+                BarProtocol::I3bar => format!("{},", json!([{ "full_text": "" }])),
+            },
+        };
+        if self.last.as_ref() != Some(&line) {
+            self.write(&line);
+            self.last = Some(line);
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::path::PathBuf;
+use std::time;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use crate::sysfs::DEFAULT_SYSFS_ROOT;
+
+/// Pops desktop notifications on battery charging state and low battery charge.
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    /// Config file to use instead of the default location.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    #[command(flatten)]
+    pub source: SourceArgs,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Watch the batteries and notify on changes (the default).
+    Run(RunArgs),
+    /// Print the current battery status once and exit.
+    Status,
+    /// Validate the config file and exit.
+    CheckConfig,
+    /// Control the running notifier.
+    Ctl {
+        #[command(subcommand)]
+        command: CtlCommand,
+    },
+    /// Summarize the recorded history: sessions, drain rates, capacity and
+    /// cycle count.
+    Report {
+        #[arg(long, value_enum, default_value_t = ReportFormat::Markdown)]
+        format: ReportFormat,
+        /// How far back the report goes, e.g. `7d`.
+        #[arg(long, value_parser = humantime::parse_duration, default_value = "30d")]
+        since: time::Duration,
+        /// File to write the report to instead of stdout.
+        #[arg(long, short, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Show a notification of the given kind using the current battery status.
+    TestNotification {
+        #[arg(value_enum)]
+        kind: NotificationKind,
+        /// Threshold to show for `threshold` or `temperature`, the most severe
+        /// one by default.
+        name: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CtlCommand {
+    /// Hold back alerts for a while, e.g. `30m`.
+    Snooze {
+        #[arg(value_parser = humantime::parse_duration)]
+        duration: time::Duration,
+    },
+    /// Hold back alerts until a battery changes state, e.g. once plugged in.
+    Pause,
+    /// Show alerts again.
+    Resume,
+    /// Print the battery status as seen by the notifier, and whether alerts
+    /// are held back.
+    Status,
+}
+
+#[derive(Debug, Args)]
+pub struct RunArgs {
+    /// Check the batteries once, notify if needed and exit.
+    #[arg(long)]
+    pub once: bool,
+    /// Poll interval, overriding the config, e.g. `5s`.
+    #[arg(long, value_parser = positive_duration)]
+    pub interval: Option<time::Duration>,
+    /// Also print a status bar line to stdout on every change.
+    #[arg(long, value_enum, value_name = "PROTOCOL")]
+    pub bar: Option<BarProtocol>,
+}
+
+#[derive(Debug, Args)]
+pub struct SourceArgs {
+    /// Where battery information is read from.
+    #[arg(
+        id = "source",
+        long = "source",
+        global = true,
+        value_enum,
+        env = "BATTERY_NOTIFIER_SOURCE",
+        default_value_t = SourceKind::Battery
+    )]
+    pub kind: SourceKind,
+    /// Root of the power_supply class for the sysfs source.
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        env = "BATTERY_NOTIFIER_SYSFS_ROOT",
+        default_value = DEFAULT_SYSFS_ROOT
+    )]
+    pub sysfs_root: PathBuf,
+    /// Replay a battery script instead of reading real batteries.
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        env = "BATTERY_NOTIFIER_SCRIPT"
+    )]
+    pub script: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SourceKind {
+    /// The `battery` crate.
+    Battery,
+    /// `/sys/class/power_supply`, or `--sysfs-root`.
+    Sysfs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BarProtocol {
+    /// One JSON object per line, for waybar's `return-type = "json"`.
+    Waybar,
+    /// The i3bar JSON protocol, for i3bar and swaybar.
+    I3bar,
+    /// One line of text, for i3blocks and polybar.
+    Plain,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+    Markdown,
+    Html,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum NotificationKind {
+    StateChanged,
+    Threshold,
+    Charged,
+    Temperature,
+    Health,
+    Drain,
+}
+
+/// A duration such as `5s` that is not zero.
+fn positive_duration(value: &str) -> Result<time::Duration, String> {
+    match humantime::parse_duration(value) {
+        Ok(duration) if duration.is_zero() => Err("must be positive".to_string()),
+        Ok(duration) => Ok(duration),
+        Err(e) => Err(e.to_string()),
+    }
+}
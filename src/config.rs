@@ -0,0 +1,984 @@
+use std::env;
+use std::error::Error;
+use std::fmt::{Debug, Display};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::action::PowerAction;
+use crate::control;
+use crate::history;
+use crate::notifier::CommandNotifier;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// How long notifications stay on screen, in milliseconds.
+    pub notification_timeout: i32,
+    /// Poll interval when kernel uevents are unavailable or disabled.
+    #[serde(deserialize_with = "duration")]
+    pub interval: time::Duration,
+    /// React to kernel `power_supply` uevents instead of polling every
+    /// `interval`.
+    pub uevents: bool,
+    /// Poll interval while uevents are received, in case a change is missed.
+    #[serde(deserialize_with = "duration")]
+    pub fallback_interval: time::Duration,
+    pub polling: PollingConfig,
+    pub estimator: EstimatorConfig,
+    /// How long a battery must report a new state before it is considered
+    /// real, to ride out firmware jitter.
+    #[serde(deserialize_with = "duration")]
+    pub state_dwell: time::Duration,
+    /// Percent the charge must rise above a threshold before it re-arms,
+    /// unless the threshold sets its own `hysteresis`.
+    pub threshold_hysteresis: f32,
+    /// Low-charge levels, sorted from the highest charge to the lowest once
+    /// loaded.
+    pub thresholds: Vec<Threshold>,
+    /// Degrees Celsius the temperature must drop below a temperature
+    /// threshold before it re-arms.
+    pub temperature_hysteresis: f32,
+    /// Battery temperature levels, sorted from the coolest to the hottest
+    /// once loaded.
+    pub temperature_thresholds: Vec<TemperatureThreshold>,
+    pub notifications: Notifications,
+    pub action: ActionConfig,
+    /// Where alerts are delivered, all of them at once.
+    pub sinks: Vec<SinkConfig>,
+    /// Accept `ctl` commands on a Unix socket.
+    pub control: bool,
+    /// Socket for `ctl` commands, by default in `$XDG_RUNTIME_DIR`.
+    pub control_socket: Option<PathBuf>,
+    /// Expose the battery status and alerts as `org.batterynotifier` on the
+    /// session bus.
+    pub dbus: bool,
+    pub mqtt: MqttConfig,
+    pub metrics: MetricsConfig,
+    pub history: HistoryConfig,
+    pub bar: BarConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            notification_timeout: 3000,
+            interval: time::Duration::from_secs(1),
+            uevents: true,
+            fallback_interval: time::Duration::from_secs(30),
+            polling: PollingConfig::default(),
+            estimator: EstimatorConfig::default(),
+            state_dwell: time::Duration::from_secs(3),
+            threshold_hysteresis: 2.0,
+            thresholds: vec![
+                Threshold::new(
+                    "warning",
+                    30.0,
+                    Urgency::Low,
+                    "Battery charge is getting low",
+                ),
+                Threshold::new("low", 15.0, Urgency::Normal, "Battery charge is low"),
+                Threshold::new(
+                    "critical",
+                    5.0,
+                    Urgency::Critical,
+                    "Battery charge is critically low",
+                ),
+            ],
+            temperature_hysteresis: 3.0,
+            temperature_thresholds: vec![
+                TemperatureThreshold::new(
+                    "warning",
+                    45.0,
+                    Urgency::Normal,
+                    "{name} is getting hot",
+                ),
+                TemperatureThreshold::new(
+                    "critical",
+                    55.0,
+                    Urgency::Critical,
+                    "{name} is overheating",
+                ),
+            ],
+            notifications: Notifications::default(),
+            action: ActionConfig::default(),
+            sinks: vec![SinkConfig::Desktop],
+            control: true,
+            control_socket: None,
+            dbus: false,
+            mqtt: MqttConfig::default(),
+            metrics: MetricsConfig::default(),
+            history: HistoryConfig::default(),
+            bar: BarConfig::default(),
+        }
+    }
+}
+
+/// Where the time to empty or to full comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EstimatorMode {
+    /// As reported by the firmware.
+    Firmware,
+    /// The notifier's own estimate when the firmware reports none or its
+    /// estimates jump around.
+    Auto,
+    /// The notifier's own estimate whenever it is confident enough.
+    Always,
+}
+
+/// The notifier's own estimate of the time to empty or to full, from the
+/// readings over the last `window`. It is only used once its confidence
+/// reaches `min_confidence` percent.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EstimatorConfig {
+    pub mode: EstimatorMode,
+    #[serde(deserialize_with = "duration")]
+    pub window: time::Duration,
+    pub min_confidence: f32,
+}
+
+impl EstimatorConfig {
+    pub fn confidence(&self) -> f32 {
+        self.min_confidence / 100.0
+    }
+}
+
+impl Default for EstimatorConfig {
+    fn default() -> Self {
+        EstimatorConfig {
+            mode: EstimatorMode::Auto,
+            window: time::Duration::from_secs(10 * 60),
+            min_confidence: 50.0,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Notifications {
+    pub state_changed: StateChangedConfig,
+    pub charged: ChargedConfig,
+    pub health: HealthConfig,
+    pub drain: DrainConfig,
+}
+
+/// Reminder to unplug once charging reaches `charge` percent or the battery
+/// reports being full. It re-arms when discharging resumes or the charge
+/// drops below the level by more than `threshold_hysteresis`.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChargedConfig {
+    pub enabled: bool,
+    pub charge: f32,
+    pub urgency: Urgency,
+    pub summary: String,
+    pub body: String,
+}
+
+impl ChargedConfig {
+    pub fn level(&self) -> f32 {
+        self.charge / 100.0
+    }
+}
+
+impl Default for ChargedConfig {
+    fn default() -> Self {
+        ChargedConfig {
+            enabled: true,
+            charge: 80.0,
+            urgency: Urgency::Normal,
+            summary: "Battery charged to {charge}%".to_string(),
+            body: "Unplug the charger to preserve battery health".to_string(),
+        }
+    }
+}
+
+/// Warns once when a battery's health, its full capacity relative to the
+/// design capacity, drops below `health` percent and once for each of the
+/// `cycles` charge cycle counts it passes. Texts may also use `{health}`,
+/// `{cycles}` and `{reason}`. Health re-arms once it rises above the level
+/// by more than `threshold_hysteresis`, e.g. after a recalibration.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    pub enabled: bool,
+    pub health: f32,
+    pub cycles: Vec<u32>,
+    pub urgency: Urgency,
+    pub summary: String,
+    pub body: String,
+}
+
+impl HealthConfig {
+    pub const PLACEHOLDERS: &'static [&'static str] = &["health", "cycles", "reason"];
+
+    pub fn level(&self) -> f32 {
+        self.health / 100.0
+    }
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            enabled: true,
+            health: 80.0,
+            cycles: Vec::new(),
+            urgency: Urgency::Normal,
+            summary: "{name} is wearing out".to_string(),
+            body: "{reason}\nHealth {health}%, {cycles} cycles".to_string(),
+        }
+    }
+}
+
+/// Warns when the battery drains `factor` times faster than usual for at
+/// least `sustain`, e.g. because of a runaway process. The usual power draw
+/// is learned while running on battery, so this needs half an hour on
+/// battery after each start, and batteries reporting their power draw.
+/// Texts may also use `{power}` and `{typical}` in watts, and the measured
+/// `{factor}`.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DrainConfig {
+    pub enabled: bool,
+    pub factor: f32,
+    #[serde(deserialize_with = "duration")]
+    pub sustain: time::Duration,
+    pub urgency: Urgency,
+    pub summary: String,
+    pub body: String,
+}
+
+impl DrainConfig {
+    pub const PLACEHOLDERS: &'static [&'static str] = &["power", "typical", "factor"];
+}
+
+impl Default for DrainConfig {
+    fn default() -> Self {
+        DrainConfig {
+            enabled: true,
+            factor: 2.0,
+            sustain: time::Duration::from_secs(2 * 60),
+            urgency: Urgency::Normal,
+            summary: "Battery draining fast".to_string(),
+            body: "Drawing {power} W, {factor}x the usual {typical} W\n{estimate}".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Urgency {
+    Low,
+    Normal,
+    Critical,
+}
+
+impl Display for Urgency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Urgency::Low => write!(f, "low"),
+            Urgency::Normal => write!(f, "normal"),
+            Urgency::Critical => write!(f, "critical"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum SinkConfig {
+    /// Desktop notifications.
+    Desktop,
+    /// One line per alert on stdout.
+    Stdout,
+    /// Runs `command` per alert, with placeholders in its arguments.
+    Command { command: Vec<String> },
+    /// POSTs a JSON payload per alert to every URL in `urls`.
+    Webhook {
+        urls: Vec<String>,
+        #[serde(default = "SinkConfig::default_timeout", deserialize_with = "duration")]
+        timeout: time::Duration,
+        #[serde(default = "SinkConfig::default_retries")]
+        retries: u32,
+    },
+}
+
+impl SinkConfig {
+    fn default_timeout() -> time::Duration {
+        time::Duration::from_secs(10)
+    }
+
+    fn default_retries() -> u32 {
+        3
+    }
+}
+
+/// A low-charge level. Its notification fires once when the combined charge
+/// drops to `charge` percent while discharging.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Threshold {
+    pub name: String,
+    pub charge: f32,
+    #[serde(default = "Threshold::default_urgency")]
+    pub urgency: Urgency,
+    #[serde(default = "Threshold::default_enabled")]
+    pub enabled: bool,
+    /// Overrides `Config::threshold_hysteresis` for this level.
+    pub hysteresis: Option<f32>,
+    pub summary: String,
+    #[serde(default = "Threshold::default_body")]
+    pub body: String,
+}
+
+impl Threshold {
+    fn new(name: &str, charge: f32, urgency: Urgency, summary: &str) -> Self {
+        Threshold {
+            name: name.to_string(),
+            charge,
+            urgency,
+            enabled: true,
+            hysteresis: None,
+            summary: summary.to_string(),
+            body: Threshold::default_body(),
+        }
+    }
+
+    /// The level as a 0..1 ratio comparable with `BatteryStatus::charge`.
+    pub fn level(&self) -> f32 {
+        self.charge / 100.0
+    }
+
+    /// The ratio the charge must exceed for the level to re-arm.
+    pub fn rearm_level(&self, default_hysteresis: f32) -> f32 {
+        (self.charge + self.hysteresis.unwrap_or(default_hysteresis)) / 100.0
+    }
+
+    fn default_urgency() -> Urgency {
+        Urgency::Normal
+    }
+
+    fn default_enabled() -> bool {
+        true
+    }
+
+    fn default_body() -> String {
+        "charge - {charge}%\n{estimate}".to_string()
+    }
+}
+
+/// A battery temperature level. Its notification fires once when the
+/// hottest battery reaches `celsius` degrees, whether charging or not, and
+/// `command` runs, e.g. to switch to a power-saver profile. Once the
+/// temperature drops below the level by `temperature_hysteresis` it re-arms
+/// and `recover_command` runs. Texts may also use `{temperature}`. A disabled
+/// level still runs its commands.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TemperatureThreshold {
+    pub name: String,
+    pub celsius: f32,
+    #[serde(default = "Threshold::default_urgency")]
+    pub urgency: Urgency,
+    #[serde(default = "Threshold::default_enabled")]
+    pub enabled: bool,
+    pub summary: String,
+    #[serde(default = "TemperatureThreshold::default_body")]
+    pub body: String,
+    #[serde(default)]
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub recover_command: Vec<String>,
+}
+
+impl TemperatureThreshold {
+    pub const PLACEHOLDERS: &'static [&'static str] = &["temperature"];
+
+    fn new(name: &str, celsius: f32, urgency: Urgency, summary: &str) -> Self {
+        TemperatureThreshold {
+            name: name.to_string(),
+            celsius,
+            urgency,
+            enabled: true,
+            summary: summary.to_string(),
+            body: TemperatureThreshold::default_body(),
+            command: Vec::new(),
+            recover_command: Vec::new(),
+        }
+    }
+
+    fn default_body() -> String {
+        "Battery temperature is {temperature}°C".to_string()
+    }
+}
+
+/// Announces every settled state change of a battery. `summary` and `body`
+/// may reference placeholders such as `{charge}`, see [`PLACEHOLDERS`].
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StateChangedConfig {
+    pub enabled: bool,
+    pub urgency: Urgency,
+    pub summary: String,
+    pub body: String,
+}
+
+impl Default for StateChangedConfig {
+    fn default() -> Self {
+        StateChangedConfig {
+            enabled: true,
+            urgency: Urgency::Normal,
+            summary: "{name} state - {state}".to_string(),
+            body: "{estimate}".to_string(),
+        }
+    }
+}
+
+/// Adaptive polling: the interval shrinks from `max_interval` to
+/// `min_interval` as the charge comes within `band` percent of the next
+/// level it is heading for. Replaces `interval` when enabled, but not
+/// `fallback_interval`: with uevents the kernel reports changes anyway.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PollingConfig {
+    pub adaptive: bool,
+    #[serde(deserialize_with = "duration")]
+    pub min_interval: time::Duration,
+    #[serde(deserialize_with = "duration")]
+    pub max_interval: time::Duration,
+    pub band: f32,
+}
+
+impl Default for PollingConfig {
+    fn default() -> Self {
+        PollingConfig {
+            adaptive: true,
+            min_interval: time::Duration::from_secs(1),
+            max_interval: time::Duration::from_secs(60),
+            band: 5.0,
+        }
+    }
+}
+
+/// Power action taken when the combined charge stays at or below `charge`
+/// percent while discharging, after a cancellable countdown notification.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ActionConfig {
+    pub enabled: bool,
+    pub charge: f32,
+    pub action: PowerAction,
+    /// Command run instead of the logind call for `action = "command"`, and
+    /// as a fallback when the logind call fails otherwise.
+    pub command: Vec<String>,
+    #[serde(deserialize_with = "duration")]
+    pub countdown: time::Duration,
+    /// Countdown notification, which may also use `{action}` and
+    /// `{countdown}`.
+    pub summary: String,
+    pub body: String,
+}
+
+impl ActionConfig {
+    /// Placeholders only the countdown notification may use.
+    pub const PLACEHOLDERS: &'static [&'static str] = &["action", "countdown"];
+
+    pub fn level(&self) -> f32 {
+        self.charge / 100.0
+    }
+}
+
+impl Default for ActionConfig {
+    fn default() -> Self {
+        ActionConfig {
+            enabled: false,
+            charge: 3.0,
+            action: PowerAction::Suspend,
+            command: Vec::new(),
+            countdown: time::Duration::from_secs(60),
+            summary: "Battery is almost empty".to_string(),
+            body: "Running {action} in {countdown} unless cancelled or plugged in".to_string(),
+        }
+    }
+}
+
+/// Placeholders every notification template may use.
+pub const PLACEHOLDERS: &[&str] = &[
+    "name",
+    "id",
+    "state",
+    "charge",
+    "time_to_full",
+    "time_to_empty",
+    "estimate",
+];
+
+/// Publishes the battery status and alerts to an MQTT broker, with Home
+/// Assistant discovery. `client_id` and `topic` may use `{host}`.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Prefix of the state, event and availability topics.
+    pub topic: String,
+    /// How often the retained state topics are refreshed when nothing
+    /// changes.
+    #[serde(deserialize_with = "duration")]
+    pub interval: time::Duration,
+    pub discovery: bool,
+    pub discovery_prefix: String,
+}
+
+impl MqttConfig {
+    pub const PLACEHOLDERS: &'static [&'static str] = &["host"];
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            enabled: false,
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "battery-notifier-{host}".to_string(),
+            username: None,
+            password: None,
+            topic: "battery-notifier/{host}".to_string(),
+            interval: time::Duration::from_secs(60),
+            discovery: true,
+            discovery_prefix: "homeassistant".to_string(),
+        }
+    }
+}
+
+/// Prometheus exporter serving `/metrics`.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub listen: SocketAddr,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            enabled: false,
+            listen: SocketAddr::from(([127, 0, 0, 1], 9898)),
+        }
+    }
+}
+
+/// Sample history kept in SQLite, by default in `$XDG_DATA_HOME`.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    pub enabled: bool,
+    pub path: Option<PathBuf>,
+    /// How long every sample is kept as read.
+    #[serde(deserialize_with = "duration")]
+    pub keep_raw: time::Duration,
+    /// Older samples are averaged over buckets this long.
+    #[serde(deserialize_with = "duration")]
+    pub downsample: time::Duration,
+    /// How long downsampled samples are kept.
+    #[serde(deserialize_with = "duration")]
+    pub keep: time::Duration,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            enabled: false,
+            path: None,
+            keep_raw: time::Duration::from_secs(7 * 24 * 60 * 60),
+            downsample: time::Duration::from_secs(5 * 60),
+            keep: time::Duration::from_secs(365 * 24 * 60 * 60),
+        }
+    }
+}
+
+/// Status bar line printed by `run --bar`. Texts may also use `{icon}`.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BarConfig {
+    pub format: String,
+    /// Used instead of `format` while charging or full.
+    pub format_charging: Option<String>,
+    /// Shown on hover by bars supporting it.
+    pub tooltip: String,
+    /// Icons for equal charge bands, from empty to full.
+    pub icons: Vec<String>,
+    /// Icons while charging or full, likewise.
+    pub charging_icons: Vec<String>,
+}
+
+impl BarConfig {
+    pub const PLACEHOLDERS: &'static [&'static str] = &["icon"];
+}
+
+impl Default for BarConfig {
+    fn default() -> Self {
+        BarConfig {
+            format: "{icon} {charge}%".to_string(),
+            format_charging: None,
+            tooltip: "{state}, {estimate}".to_string(),
+            icons: ["\u{f244}", "\u{f243}", "\u{f242}", "\u{f241}", "\u{f240}"]
+                .map(String::from)
+                .to_vec(),
+            charging_icons: vec!["\u{f1e6}".to_string()],
+        }
+    }
+}
+
+/// Replaces `{key}` placeholders in `template` with their values.
+pub fn render(template: &str, vars: &[(&str, String)]) -> String {
+    let mut out = template.to_string();
+    for (key, value) in vars {
+        out = out.replace(&format!("{{{}}}", key), value);
+    }
+    out
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "failed to read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config {}: {}", path.display(), e),
+            ConfigError::Invalid(msg) => write!(f, "invalid config: {}", msg),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<time::Duration, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    humantime::parse_duration(&s).map_err(serde::de::Error::custom)
+}
+
+/// `$XDG_CONFIG_HOME/battery-notifier/config.toml`, falling back to
+/// `~/.config` when `XDG_CONFIG_HOME` is unset.
+pub fn default_path() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME").filter(|v| !v.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("battery-notifier").join("config.toml"))
+}
+
+impl Config {
+    /// Loads the config from `path`, or from [`default_path`] when `None`.
+    /// A missing default file yields the defaults, a missing explicit file
+    /// is an error.
+    pub fn load(path: Option<&Path>) -> Result<Config, ConfigError> {
+        let (path, explicit) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Config::default()),
+            },
+        };
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !explicit => {
+                return Ok(Config::default())
+            }
+            Err(e) => return Err(ConfigError::Read(path, e)),
+        };
+        let mut config: Config =
+            toml::from_str(&content).map_err(|e| ConfigError::Parse(path.clone(), e))?;
+        config.validate()?;
+        config
+            .thresholds
+            .sort_by(|a, b| b.charge.total_cmp(&a.charge));
+        config
+            .temperature_thresholds
+            .sort_by(|a, b| a.celsius.total_cmp(&b.celsius));
+        Ok(config)
+    }
+
+    pub fn control_socket(&self) -> PathBuf {
+        self.control_socket
+            .clone()
+            .unwrap_or_else(control::default_socket_path)
+    }
+
+    pub fn history_path(&self) -> Option<PathBuf> {
+        self.history.path.clone().or_else(history::default_path)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.notification_timeout < 0 {
+            return Err(ConfigError::Invalid(
+                "notification_timeout must not be negative".to_string(),
+            ));
+        }
+        if self.interval.is_zero() || self.fallback_interval.is_zero() {
+            return Err(ConfigError::Invalid(
+                "interval and fallback_interval must be positive".to_string(),
+            ));
+        }
+        let polling = &self.polling;
+        if polling.min_interval.is_zero() || polling.min_interval > polling.max_interval {
+            return Err(ConfigError::Invalid(
+                "polling.min_interval must be positive and at most polling.max_interval"
+                    .to_string(),
+            ));
+        }
+        if polling.band <= 0.0 || polling.band > 100.0 {
+            return Err(ConfigError::Invalid(format!(
+                "polling.band must be between 0 and 100, got {}",
+                polling.band
+            )));
+        }
+        validate_percent("threshold_hysteresis", self.threshold_hysteresis)?;
+        if self.estimator.window < time::Duration::from_secs(60) {
+            return Err(ConfigError::Invalid(
+                "estimator.window must be at least 1m".to_string(),
+            ));
+        }
+        validate_percent("estimator.min_confidence", self.estimator.min_confidence)?;
+        let state_changed = &self.notifications.state_changed;
+        validate_template(
+            "notifications.state_changed",
+            "summary",
+            &state_changed.summary,
+            &[],
+        )?;
+        validate_template(
+            "notifications.state_changed",
+            "body",
+            &state_changed.body,
+            &[],
+        )?;
+        let charged = &self.notifications.charged;
+        validate_percent("notifications.charged.charge", charged.charge)?;
+        validate_template("notifications.charged", "summary", &charged.summary, &[])?;
+        validate_template("notifications.charged", "body", &charged.body, &[])?;
+        let health = &self.notifications.health;
+        validate_percent("notifications.health.health", health.health)?;
+        let extra = HealthConfig::PLACEHOLDERS;
+        validate_template("notifications.health", "summary", &health.summary, extra)?;
+        validate_template("notifications.health", "body", &health.body, extra)?;
+        let drain = &self.notifications.drain;
+        if drain.factor <= 1.0 {
+            return Err(ConfigError::Invalid(format!(
+                "notifications.drain.factor must be above 1, got {}",
+                drain.factor
+            )));
+        }
+        let extra = DrainConfig::PLACEHOLDERS;
+        validate_template("notifications.drain", "summary", &drain.summary, extra)?;
+        validate_template("notifications.drain", "body", &drain.body, extra)?;
+        for (idx, threshold) in self.thresholds.iter().enumerate() {
+            let name = format!("thresholds[{}]", idx);
+            if threshold.name.is_empty() {
+                return Err(ConfigError::Invalid(format!(
+                    "{}: name must not be empty",
+                    name
+                )));
+            }
+            validate_percent(&format!("{}.charge", name), threshold.charge)?;
+            if let Some(hysteresis) = threshold.hysteresis {
+                validate_percent(&format!("{}.hysteresis", name), hysteresis)?;
+            }
+            if self.thresholds[..idx]
+                .iter()
+                .any(|t| t.name == threshold.name || t.charge == threshold.charge)
+            {
+                return Err(ConfigError::Invalid(format!(
+                    "{}: duplicate threshold name {:?} or charge {}",
+                    name, threshold.name, threshold.charge
+                )));
+            }
+            validate_template(&name, "summary", &threshold.summary, &[])?;
+            validate_template(&name, "body", &threshold.body, &[])?;
+        }
+        if !(0.0..=50.0).contains(&self.temperature_hysteresis) {
+            return Err(ConfigError::Invalid(format!(
+                "temperature_hysteresis must be between 0 and 50, got {}",
+                self.temperature_hysteresis
+            )));
+        }
+        let thresholds = &self.temperature_thresholds;
+        for (idx, threshold) in thresholds.iter().enumerate() {
+            let name = format!("temperature_thresholds[{}]", idx);
+            if threshold.name.is_empty() {
+                return Err(ConfigError::Invalid(format!(
+                    "{}: name must not be empty",
+                    name
+                )));
+            }
+            if !(0.0..=150.0).contains(&threshold.celsius) {
+                return Err(ConfigError::Invalid(format!(
+                    "{}.celsius must be between 0 and 150, got {}",
+                    name, threshold.celsius
+                )));
+            }
+            if thresholds[..idx]
+                .iter()
+                .any(|t| t.name == threshold.name || t.celsius == threshold.celsius)
+            {
+                return Err(ConfigError::Invalid(format!(
+                    "{}: duplicate temperature threshold name {:?} or celsius {}",
+                    name, threshold.name, threshold.celsius
+                )));
+            }
+            let extra = TemperatureThreshold::PLACEHOLDERS;
+            validate_template(&name, "summary", &threshold.summary, extra)?;
+            validate_template(&name, "body", &threshold.body, extra)?;
+        }
+        let action = &self.action;
+        validate_percent("action.charge", action.charge)?;
+        if action.action == PowerAction::Command && action.command.is_empty() {
+            return Err(ConfigError::Invalid(
+                "action.command is required for action = \"command\"".to_string(),
+            ));
+        }
+        let extra = ActionConfig::PLACEHOLDERS;
+        validate_template("action", "summary", &action.summary, extra)?;
+        validate_template("action", "body", &action.body, extra)?;
+        for (idx, sink) in self.sinks.iter().enumerate() {
+            let name = format!("sinks[{}]", idx);
+            match sink {
+                SinkConfig::Command { command } => {
+                    if command.is_empty() {
+                        return Err(ConfigError::Invalid(format!(
+                            "{}: command must not be empty",
+                            name
+                        )));
+                    }
+                    for arg in command {
+                        validate_template(&name, "command", arg, CommandNotifier::PLACEHOLDERS)?;
+                    }
+                }
+                SinkConfig::Webhook { urls, .. } => {
+                    if urls.is_empty() {
+                        return Err(ConfigError::Invalid(format!(
+                            "{}: urls must not be empty",
+                            name
+                        )));
+                    }
+                    if let Some(url) = urls
+                        .iter()
+                        .find(|u| !u.starts_with("http://") && !u.starts_with("https://"))
+                    {
+                        return Err(ConfigError::Invalid(format!(
+                            "{}: {:?} is not an http(s) URL",
+                            name, url
+                        )));
+                    }
+                }
+                SinkConfig::Desktop | SinkConfig::Stdout => {}
+            }
+        }
+        let bar = &self.bar;
+        let extra = BarConfig::PLACEHOLDERS;
+        validate_template("bar", "format", &bar.format, extra)?;
+        if let Some(format) = &bar.format_charging {
+            validate_template("bar", "format_charging", format, extra)?;
+        }
+        validate_template("bar", "tooltip", &bar.tooltip, extra)?;
+        if bar.icons.is_empty() || bar.charging_icons.is_empty() {
+            return Err(ConfigError::Invalid(
+                "bar.icons and bar.charging_icons must not be empty".to_string(),
+            ));
+        }
+        let mqtt = &self.mqtt;
+        if mqtt.interval.is_zero() {
+            return Err(ConfigError::Invalid(
+                "mqtt.interval must be positive".to_string(),
+            ));
+        }
+        for (field, topic) in [
+            ("topic", &mqtt.topic),
+            ("discovery_prefix", &mqtt.discovery_prefix),
+        ] {
+            if topic.is_empty() || topic.contains(['+', '#']) {
+                return Err(ConfigError::Invalid(format!(
+                    "mqtt.{} must be a non-empty topic without wildcards",
+                    field
+                )));
+            }
+        }
+        validate_placeholders(
+            "mqtt",
+            "client_id",
+            &mqtt.client_id,
+            MqttConfig::PLACEHOLDERS,
+        )?;
+        validate_placeholders("mqtt", "topic", &mqtt.topic, MqttConfig::PLACEHOLDERS)?;
+        let history = &self.history;
+        if history.downsample.as_secs() == 0 {
+            return Err(ConfigError::Invalid(
+                "history.downsample must be at least 1s".to_string(),
+            ));
+        }
+        if history.keep < history.keep_raw {
+            return Err(ConfigError::Invalid(
+                "history.keep must not be shorter than history.keep_raw".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn validate_percent(name: &str, value: f32) -> Result<(), ConfigError> {
+    if !(0.0..=100.0).contains(&value) {
+        return Err(ConfigError::Invalid(format!(
+            "{} must be between 0 and 100, got {}",
+            name, value
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that `template` only uses [`PLACEHOLDERS`] and the `extra` ones.
+fn validate_template(
+    name: &str,
+    field: &str,
+    template: &str,
+    extra: &[&str],
+) -> Result<(), ConfigError> {
+    validate_placeholders(name, field, template, &[PLACEHOLDERS, extra].concat())
+}
+
+/// Checks that `template` only uses the `allowed` placeholders.
+fn validate_placeholders(
+    name: &str,
+    field: &str,
+    template: &str,
+    allowed: &[&str],
+) -> Result<(), ConfigError> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..].find('}').ok_or_else(|| {
+            ConfigError::Invalid(format!("{}.{}: unclosed placeholder", name, field))
+        })?;
+        let key = &rest[start + 1..start + end];
+        if !allowed.contains(&key) {
+            return Err(ConfigError::Invalid(format!(
+                "{}.{}: unknown placeholder {{{}}}, expected one of {:?}",
+                name, field, key, allowed
+            )));
+        }
+        rest = &rest[start + end + 1..];
+    }
+    Ok(())
+}
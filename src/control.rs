@@ -0,0 +1,160 @@
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+use crate::events::Event;
+
+/// How long a client waits for the daemon, and the daemon for its main loop.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A command sent to a running daemon. On the wire each request is one line
+/// of text, answered with free text; answers to failed requests start with
+/// `error:`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+    /// Hold back alerts for the given time.
+    Snooze(Duration),
+    /// Hold back alerts until a battery changes state.
+    Pause,
+    /// End a snooze or pause.
+    Resume,
+    Status,
+}
+
+impl Display for Request {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Request::Snooze(duration) => write!(f, "snooze {}", duration.as_secs()),
+            Request::Pause => write!(f, "pause"),
+            Request::Resume => write!(f, "resume"),
+            Request::Status => write!(f, "status"),
+        }
+    }
+}
+
+impl FromStr for Request {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let request = match (words.next(), words.next()) {
+            (Some("snooze"), Some(secs)) => secs
+                .parse()
+                .map(|secs| Request::Snooze(Duration::from_secs(secs)))
+                .map_err(|_| format!("invalid snooze duration {:?}", secs))?,
+            (Some("pause"), None) => Request::Pause,
+            (Some("resume"), None) => Request::Resume,
+            (Some("status"), None) => Request::Status,
+            _ => return Err(format!("unknown request {:?}", s)),
+        };
+        match words.next() {
+            Some(_) => Err(format!("unknown request {:?}", s)),
+            None => Ok(request),
+        }
+    }
+}
+
+/// `$XDG_RUNTIME_DIR/battery-notifier.sock`, or a per-user path in `/tmp`.
+pub fn default_socket_path() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("battery-notifier.sock"),
+        _ => {
+            // SAFETY: getuid(2) has no preconditions and cannot fail.
+            let uid = unsafe { libc::getuid() };
+            PathBuf::from(format!("/tmp/battery-notifier-{}.sock", uid))
+        }
+    }
+}
+
+/// Removes the socket file when the daemon exits.
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Binds the control socket at `path` and forwards each request as
+/// [`Event::Control`] from a background thread. Fails if another daemon
+/// already listens there.
+pub fn listen(path: &Path, tx: Sender<Event>) -> io::Result<ControlSocket> {
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("another daemon is listening on {:?}", path),
+        ));
+    }
+    // Left over from a daemon that did not exit cleanly.
+    let _ = fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| serve(stream, &tx));
+            match result {
+                Ok(true) => {}
+                Ok(false) => {
+                    debug!("event loop gone, stop serving the control socket");
+                    return;
+                }
+                Err(e) => warn!("control connection failed: {}", e),
+            }
+        }
+    });
+    Ok(ControlSocket {
+        path: path.to_path_buf(),
+    })
+}
+
+/// Answers one request. Returns false once the main loop is gone.
+fn serve(mut stream: UnixStream, tx: &Sender<Event>) -> io::Result<bool> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    let mut line = String::new();
+    if BufReader::new(&stream).read_line(&mut line)? == 0 {
+        // A probe from another daemon checking whether this one is alive.
+        return Ok(true);
+    }
+    let reply = match line.trim().parse::<Request>() {
+        Ok(request) => {
+            info!("control request: {}", request);
+            let (reply_tx, reply_rx) = mpsc::channel();
+            if tx.send(Event::Control(request, reply_tx)).is_err() {
+                return Ok(false);
+            }
+            reply_rx
+                .recv_timeout(TIMEOUT)
+                .unwrap_or_else(|_| "error: no answer from the main loop".to_string())
+        }
+        Err(e) => format!("error: {}", e),
+    };
+    stream.write_all(reply.as_bytes())?;
+    Ok(true)
+}
+
+/// Sends `request` to the daemon listening at `path` and returns its answer.
+pub fn send(path: &Path, request: Request) -> io::Result<String> {
+    let mut stream = UnixStream::connect(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("cannot reach the daemon at {:?}: {}", path, e),
+        )
+    })?;
+    stream.set_read_timeout(Some(TIMEOUT * 2))?;
+    writeln!(stream, "{}", request)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
@@ -0,0 +1,230 @@
+use std::error::Error;
+use std::mem;
+use std::time::Instant;
+
+use battery::units::energy::watt_hour;
+use battery::units::power::watt;
+use battery::units::time::second;
+use battery::units::Time;
+use log::warn;
+use zbus::blocking::{connection, Connection};
+use zbus::object_server::SignalEmitter;
+use zbus::zvariant::{Type, Value};
+
+use crate::notifier::{Alert, AlertKind, Notifier};
+use crate::publisher::Publisher;
+use crate::source::BatteryStatus;
+
+pub const NAME: &str = "org.batterynotifier";
+pub const PATH: &str = "/org/batterynotifier";
+
+/// A battery as exposed over D-Bus. As with UPower, unknown times and
+/// energy values are 0.
+#[derive(Debug, Clone, PartialEq, Type, Value)]
+pub struct BatteryInfo {
+    id: String,
+    state: String,
+    percentage: f64,
+    time_to_empty: i64,
+    time_to_full: i64,
+    energy: f64,
+    energy_full: f64,
+    energy_rate: f64,
+}
+
+fn seconds(time: Option<Time>) -> i64 {
+    match time.map(|t| t.get::<second>()) {
+        Some(secs) if secs.is_finite() && secs > 0.0 => secs.round() as i64,
+        _ => 0,
+    }
+}
+
+impl From<&BatteryStatus> for BatteryInfo {
+    fn from(status: &BatteryStatus) -> Self {
+        BatteryInfo {
+            id: status.id.clone(),
+            state: status.state.to_string(),
+            percentage: (status.charge * 100.0).into(),
+            time_to_empty: seconds(status.time_to_empty),
+            time_to_full: seconds(status.time_to_full),
+            energy: status.energy.map_or(0.0, |e| e.get::<watt_hour>().into()),
+            energy_full: status
+                .energy_full
+                .map_or(0.0, |e| e.get::<watt_hour>().into()),
+            energy_rate: status.energy_rate.map_or(0.0, |p| p.get::<watt>().into()),
+        }
+    }
+}
+
+impl Default for BatteryInfo {
+    fn default() -> Self {
+        BatteryInfo {
+            id: String::new(),
+            state: battery::State::Unknown.to_string(),
+            percentage: 0.0,
+            time_to_empty: 0,
+            time_to_full: 0,
+            energy: 0.0,
+            energy_full: 0.0,
+            energy_rate: 0.0,
+        }
+    }
+}
+
+/// The `org.batterynotifier` interface. Top-level properties describe the
+/// combined system battery, `Batteries` every battery on its own.
+#[derive(Default)]
+struct Service {
+    system: BatteryInfo,
+    batteries: Vec<BatteryInfo>,
+}
+
+#[zbus::interface(name = "org.batterynotifier")]
+impl Service {
+    #[zbus(property)]
+    fn percentage(&self) -> f64 {
+        self.system.percentage
+    }
+
+    #[zbus(property)]
+    fn state(&self) -> String {
+        self.system.state.clone()
+    }
+
+    #[zbus(property)]
+    fn time_to_empty(&self) -> i64 {
+        self.system.time_to_empty
+    }
+
+    #[zbus(property)]
+    fn time_to_full(&self) -> i64 {
+        self.system.time_to_full
+    }
+
+    #[zbus(property)]
+    fn energy_rate(&self) -> f64 {
+        self.system.energy_rate
+    }
+
+    #[zbus(property)]
+    fn batteries(&self) -> Vec<BatteryInfo> {
+        self.batteries.clone()
+    }
+
+    /// Emitted for every alert, whichever sinks are configured. `threshold`
+    /// is empty unless `kind` is "threshold" or "temperature".
+    #[zbus(signal)]
+    async fn alert(
+        emitter: &SignalEmitter<'_>,
+        kind: &str,
+        threshold: &str,
+        urgency: &str,
+        summary: &str,
+        body: &str,
+        battery: &str,
+    ) -> zbus::Result<()>;
+}
+
+/// Serves [`Service`] on the session bus and keeps its properties current.
+pub struct DbusService {
+    connection: Connection,
+}
+
+impl DbusService {
+    pub fn start() -> zbus::Result<Self> {
+        let connection = connection::Builder::session()?
+            .name(NAME)?
+            .serve_at(PATH, Service::default())?
+            .build()?;
+        Ok(DbusService { connection })
+    }
+
+    /// Sink emitting the `Alert` signal.
+    pub fn signals(&self) -> DbusNotifier {
+        DbusNotifier {
+            connection: self.connection.clone(),
+        }
+    }
+
+    /// Updates the properties, emitting `PropertiesChanged` for those whose
+    /// value changed.
+    fn update(
+        &self,
+        batteries: &[&BatteryStatus],
+        system: Option<&BatteryStatus>,
+    ) -> zbus::Result<()> {
+        let iface = self
+            .connection
+            .object_server()
+            .interface::<_, Service>(PATH)?;
+        let emitter = iface.signal_emitter();
+        let mut service = iface.get_mut();
+        let old = mem::replace(
+            &mut service.system,
+            system.map(Into::into).unwrap_or_default(),
+        );
+        let new = &service.system;
+        if old.percentage != new.percentage {
+            zbus::block_on(service.percentage_changed(emitter))?;
+        }
+        if old.state != new.state {
+            zbus::block_on(service.state_changed(emitter))?;
+        }
+        if old.time_to_empty != new.time_to_empty {
+            zbus::block_on(service.time_to_empty_changed(emitter))?;
+        }
+        if old.time_to_full != new.time_to_full {
+            zbus::block_on(service.time_to_full_changed(emitter))?;
+        }
+        if old.energy_rate != new.energy_rate {
+            zbus::block_on(service.energy_rate_changed(emitter))?;
+        }
+        let batteries: Vec<BatteryInfo> = batteries.iter().map(|b| (*b).into()).collect();
+        if service.batteries != batteries {
+            service.batteries = batteries;
+            zbus::block_on(service.batteries_changed(emitter))?;
+        }
+        Ok(())
+    }
+}
+
+impl Publisher for DbusService {
+    fn publish(
+        &mut self,
+        batteries: &[&BatteryStatus],
+        system: Option<&BatteryStatus>,
+        _now: Instant,
+    ) {
+        if let Err(e) = self.update(batteries, system) {
+            warn!("failed to update D-Bus properties: {}", e);
+        }
+    }
+}
+
+/// Emits the `Alert` signal of a [`DbusService`].
+pub struct DbusNotifier {
+    connection: Connection,
+}
+
+impl Notifier for DbusNotifier {
+    fn notify(&mut self, alert: &Alert) -> Result<(), Box<dyn Error>> {
+        let iface = self
+            .connection
+            .object_server()
+            .interface::<_, Service>(PATH)?;
+        let threshold = match &alert.kind {
+            AlertKind::Threshold(name) | AlertKind::Temperature(name) => name.as_str(),
+            _ => "",
+        };
+        zbus::block_on(Service::alert(
+            iface.signal_emitter(),
+            &alert.kind.to_string(),
+            threshold,
+            &alert.urgency.to_string(),
+            &alert.summary,
+            &alert.body,
+            &alert.status.id,
+        ))?;
+        Ok(())
+    }
+}
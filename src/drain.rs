@@ -0,0 +1,315 @@
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use battery::units::power::watt;
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::config::DrainConfig;
+use crate::monitor;
+use crate::source::BatteryStatus;
+use crate::state;
+
+/// Time constant of the typical power draw, counting time on battery only.
+const BASELINE_TAU: Duration = Duration::from_secs(6 * 60 * 60);
+/// Time constant of the current power draw.
+const CURRENT_TAU: Duration = Duration::from_secs(60);
+/// Time on battery before the typical draw is trusted.
+const WARMUP: Duration = Duration::from_secs(30 * 60);
+/// Longer gaps between readings, as over a suspend, are not counted.
+const MAX_GAP: Duration = Duration::from_secs(5 * 60);
+
+/// Part of the alert level the draw must drop below to re-arm it.
+const REARM: f64 = 0.8;
+/// Time on battery between saves of the typical draw.
+const SAVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// `drain.json` in the state directory.
+pub fn default_path() -> Option<PathBuf> {
+    Some(state::dir()?.join("drain.json"))
+}
+
+/// The typical draw as saved between runs.
+#[derive(Debug, Serialize, Deserialize)]
+struct Saved {
+    /// Watts.
+    typical: f64,
+    /// Seconds on battery it was learned from.
+    observed: f64,
+}
+
+/// A discharge rate well above the typical one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Drain {
+    /// Current draw in watts.
+    pub power: f64,
+    /// Typical draw in watts.
+    pub typical: f64,
+}
+
+/// Learns the typical power draw on battery and reports once per discharge
+/// when the current draw stays above `factor` times it for `sustain`. The
+/// alert re-arms once the draw is clearly back below that level or charging
+/// resumes. The typical draw is saved to a file now and then, so that it
+/// carries over to the next run.
+pub struct DrainDetector {
+    path: Option<PathBuf>,
+    baseline: Option<f64>,
+    current: Option<f64>,
+    observed: Duration,
+    /// `observed` when the typical draw was last saved.
+    saved: Duration,
+    last: Option<Instant>,
+    above_since: Option<Instant>,
+    notified: bool,
+}
+
+fn smooth(mean: Option<f64>, value: f64, dt: Duration, tau: Duration) -> f64 {
+    match mean {
+        Some(mean) => {
+            let alpha = 1.0 - (-dt.as_secs_f64() / tau.as_secs_f64()).exp();
+            mean + alpha * (value - mean)
+        }
+        None => value,
+    }
+}
+
+impl DrainDetector {
+    /// Starts from the typical draw saved at `path`. Without a path it is
+    /// learned afresh and not saved.
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let saved: Option<Saved> = path
+            .as_deref()
+            .and_then(|path| state::load(path, "typical power draw"));
+        let saved = saved
+            .filter(|s| s.typical.is_finite() && s.typical > 0.0)
+            .and_then(|s| Some((s.typical, Duration::try_from_secs_f64(s.observed).ok()?)));
+        let observed = saved.map_or(Duration::ZERO, |(_, observed)| observed);
+        DrainDetector {
+            path,
+            baseline: saved.map(|(typical, _)| typical),
+            current: None,
+            observed,
+            saved: observed,
+            last: None,
+            above_since: None,
+            notified: false,
+        }
+    }
+
+    /// Folds `power` into the typical draw: the plain average until there
+    /// is `BASELINE_TAU` of it, then a moving average over about as long.
+    fn observe(&mut self, power: f64, dt: Duration) {
+        self.observed += dt;
+        self.baseline = Some(match self.baseline {
+            Some(baseline) if self.observed < BASELINE_TAU => {
+                let weight = dt.as_secs_f64() / self.observed.as_secs_f64();
+                baseline + weight * (power - baseline)
+            }
+            baseline => smooth(baseline, power, dt, BASELINE_TAU),
+        });
+        if self.observed >= self.saved + SAVE_INTERVAL {
+            self.save();
+        }
+    }
+
+    fn save(&mut self) {
+        self.saved = self.observed;
+        let (path, typical) = match (&self.path, self.baseline) {
+            (Some(path), Some(typical)) => (path, typical),
+            _ => return,
+        };
+        debug!("typical power draw now {:.1} W", typical);
+        let saved = Saved {
+            typical,
+            observed: self.observed.as_secs_f64(),
+        };
+        state::save(path, &saved, "typical power draw");
+    }
+
+    /// Adds the reading of `system` taken at `now`, returning the drain to
+    /// notify about, if any.
+    pub fn update(
+        &mut self,
+        config: &DrainConfig,
+        system: &BatteryStatus,
+        now: Instant,
+    ) -> Option<Drain> {
+        let power = system
+            .energy_rate
+            .map(|p| f64::from(p.get::<watt>()))
+            .filter(|p| p.is_finite() && *p > 0.0);
+        let power = match power {
+            Some(power) if monitor::is_discharging(system.state) => power,
+            _ => {
+                // Only the discharge rate is tracked, starting afresh after
+                // every charge.
+                self.current = None;
+                self.last = None;
+                self.above_since = None;
+                self.notified = false;
+                return None;
+            }
+        };
+        let dt = match self.last {
+            Some(last) => now.duration_since(last),
+            None => Duration::ZERO,
+        };
+        self.last = Some(now);
+        if dt > MAX_GAP {
+            self.current = None;
+        }
+        let dt = dt.min(MAX_GAP);
+        let current = smooth(self.current, power, dt, CURRENT_TAU);
+        self.current = Some(current);
+
+        let typical = self.baseline.filter(|_| self.observed >= WARMUP);
+        let limit = typical.map(|typical| typical * f64::from(config.factor));
+        let above = limit.is_some_and(|limit| current > limit);
+        if !above {
+            self.above_since = None;
+            if limit.is_none_or(|limit| current < limit * REARM) {
+                self.notified = false;
+            }
+            // An anomaly is kept out of what counts as typical.
+            self.observe(power, dt);
+            return None;
+        }
+        let since = *self.above_since.get_or_insert(now);
+        if self.notified || now.duration_since(since) < config.sustain {
+            return None;
+        }
+        self.notified = true;
+        Some(Drain {
+            power: current,
+            typical: typical?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+
+    use battery::units::Power;
+    use battery::State;
+
+    use super::*;
+
+    /// Feeds a reading every 10s from `start` for `secs` seconds, and
+    /// returns the seconds since `start` at which a drain was reported.
+    fn feed(
+        detector: &mut DrainDetector,
+        start: Instant,
+        from: u64,
+        secs: u64,
+        state: State,
+        watts: f32,
+    ) -> Vec<u64> {
+        let config = DrainConfig::default();
+        let status = BatteryStatus {
+            energy_rate: Some(Power::new::<watt>(watts)),
+            ..BatteryStatus::new("BAT0".to_string(), state, 0.5)
+        };
+        (from..from + secs)
+            .step_by(10)
+            .filter(|t| {
+                let now = start + Duration::from_secs(*t);
+                detector.update(&config, &status, now).is_some()
+            })
+            .collect()
+    }
+
+    /// A detector that has seen 40 minutes of a steady 10 W.
+    fn learned(start: Instant) -> DrainDetector {
+        let mut detector = DrainDetector::load(None);
+        assert!(feed(&mut detector, start, 0, 2400, State::Discharging, 10.0).is_empty());
+        detector
+    }
+
+    #[test]
+    fn waits_for_warmup() {
+        let start = Instant::now();
+        let mut detector = DrainDetector::load(None);
+        feed(&mut detector, start, 0, 1200, State::Discharging, 10.0);
+        let alerts = feed(&mut detector, start, 1200, 300, State::Discharging, 40.0);
+        assert!(alerts.is_empty(), "{:?}", alerts);
+    }
+
+    #[test]
+    fn reports_sustained_drain_once() {
+        let start = Instant::now();
+        let mut detector = learned(start);
+        let alerts = feed(&mut detector, start, 2400, 600, State::Discharging, 30.0);
+        // The smoothed draw passes 20 W after about 40s, then has to stay
+        // above it for the 2m sustain.
+        assert_eq!(alerts.len(), 1, "{:?}", alerts);
+        assert!(
+            (2400 + 150..2400 + 180).contains(&alerts[0]),
+            "{:?}",
+            alerts
+        );
+    }
+
+    #[test]
+    fn short_spike_is_ignored() {
+        let start = Instant::now();
+        let mut detector = learned(start);
+        let mut alerts = feed(&mut detector, start, 2400, 90, State::Discharging, 30.0);
+        alerts.extend(feed(
+            &mut detector,
+            start,
+            2490,
+            300,
+            State::Discharging,
+            10.0,
+        ));
+        assert!(alerts.is_empty(), "{:?}", alerts);
+    }
+
+    #[test]
+    fn rearms_well_below_limit() {
+        let start = Instant::now();
+        let mut detector = learned(start);
+        assert_eq!(
+            feed(&mut detector, start, 2400, 600, State::Discharging, 30.0).len(),
+            1
+        );
+        // Just below the 20 W limit, but above the re-arm level.
+        feed(&mut detector, start, 3000, 300, State::Discharging, 18.0);
+        let alerts = feed(&mut detector, start, 3300, 600, State::Discharging, 30.0);
+        assert!(alerts.is_empty(), "{:?}", alerts);
+        feed(&mut detector, start, 3900, 300, State::Discharging, 10.0);
+        let alerts = feed(&mut detector, start, 4200, 600, State::Discharging, 30.0);
+        assert_eq!(alerts.len(), 1, "{:?}", alerts);
+    }
+
+    #[test]
+    fn rearms_when_charging() {
+        let start = Instant::now();
+        let mut detector = learned(start);
+        assert_eq!(
+            feed(&mut detector, start, 2400, 600, State::Discharging, 30.0).len(),
+            1
+        );
+        feed(&mut detector, start, 3000, 60, State::Charging, 30.0);
+        let alerts = feed(&mut detector, start, 3060, 600, State::Discharging, 30.0);
+        assert_eq!(alerts.len(), 1, "{:?}", alerts);
+    }
+
+    #[test]
+    fn typical_draw_carries_over() {
+        let dir = std::env::temp_dir().join(format!("battery-notifier-drain-{}", process::id()));
+        let path = dir.join("drain.json");
+        let start = Instant::now();
+        let mut detector = DrainDetector::load(Some(path.clone()));
+        feed(&mut detector, start, 0, 2400, State::Discharging, 10.0);
+
+        // No warmup after a restart.
+        let mut detector = DrainDetector::load(Some(path));
+        let alerts = feed(&mut detector, start, 0, 600, State::Discharging, 30.0);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(alerts.len(), 1, "{:?}", alerts);
+    }
+}
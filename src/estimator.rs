@@ -0,0 +1,436 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use battery::units::energy::watt_hour;
+use battery::units::power::watt;
+use battery::units::time::second;
+use battery::units::Time;
+use log::debug;
+
+use crate::config::{EstimatorConfig, EstimatorMode};
+use crate::monitor;
+use crate::source::BatteryStatus;
+
+/// Fewest readings a regression is fitted to.
+const MIN_POINTS: usize = 3;
+/// How far the end times implied by the firmware estimates may spread,
+/// relative to the estimate, before they count as noisy.
+const MAX_FIRMWARE_SPREAD: f64 = 0.3;
+
+/// A time to empty or to full, with a confidence from 0 to 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    pub secs: f64,
+    pub confidence: f64,
+}
+
+/// A reading, with the charge level as a fraction of the full battery so
+/// that batteries without energy readings work as well.
+struct Point {
+    at: Instant,
+    level: f64,
+    /// The firmware time to empty or to full, in seconds.
+    firmware: Option<f64>,
+}
+
+/// Estimates the time to empty or to full of one battery from its recent
+/// readings: an EWMA of the reported power draw, and a linear regression of
+/// the charge level over the last `window`. Each is weighted by its own
+/// confidence, the fit of the regression and the steadiness of the power
+/// draw, both growing as the window fills. Starts over whenever the battery
+/// switches between charging and discharging.
+pub struct Estimator {
+    window: Duration,
+    discharging: Option<bool>,
+    points: VecDeque<Point>,
+    /// Smoothed rate in fractions per second, its variance and when it was
+    /// last updated.
+    ewma: Option<(f64, f64, Instant)>,
+    /// Power readings folded into `ewma`.
+    rates: usize,
+}
+
+impl Estimator {
+    pub fn new(window: Duration) -> Self {
+        Estimator {
+            window,
+            discharging: None,
+            points: VecDeque::new(),
+            ewma: None,
+            rates: 0,
+        }
+    }
+
+    /// Adds a reading taken at `now` and returns the estimate it gives, if
+    /// any.
+    pub fn update(&mut self, status: &BatteryStatus, now: Instant) -> Option<Estimate> {
+        let discharging = monitor::is_discharging(status.state);
+        if self.discharging != Some(discharging) {
+            self.discharging = Some(discharging);
+            self.points.clear();
+            self.ewma = None;
+            self.rates = 0;
+        }
+        let full = status
+            .energy_full
+            .map(|e| f64::from(e.get::<watt_hour>()))
+            .filter(|full| *full > 0.0);
+        let level = match (status.energy, full) {
+            (Some(energy), Some(full)) => f64::from(energy.get::<watt_hour>()) / full,
+            _ => f64::from(status.charge),
+        };
+        let firmware = match discharging {
+            true => status.time_to_empty,
+            false => status.time_to_full,
+        }
+        .map(|t| f64::from(t.get::<second>()))
+        .filter(|secs| secs.is_finite() && *secs > 0.0);
+        self.points.push_back(Point {
+            at: now,
+            level,
+            firmware,
+        });
+        while self
+            .points
+            .front()
+            .is_some_and(|p| now.duration_since(p.at) > self.window)
+        {
+            self.points.pop_front();
+        }
+        // Fractions of the full battery per second.
+        let rate = match (status.energy_rate, full) {
+            (Some(rate), Some(full)) => Some(f64::from(rate.get::<watt>()) / full / 3600.0),
+            _ => None,
+        };
+        if let Some(rate) = rate.filter(|r| r.is_finite() && *r > 0.0) {
+            self.fold(rate, now);
+        }
+
+        let estimates = [self.regression(), self.smoothed()];
+        let (mut sum, mut weights, mut confidence) = (0.0, 0.0, 0.0);
+        for (rate, weight) in estimates.into_iter().flatten() {
+            sum += rate * weight;
+            weights += weight;
+            confidence += weight * weight;
+        }
+        if weights <= 0.0 || sum <= 0.0 {
+            return None;
+        }
+        let rate = sum / weights;
+        let left = if discharging { level } else { 1.0 - level };
+        let estimate = Estimate {
+            secs: left.max(0.0) / rate,
+            confidence: confidence / weights,
+        };
+        debug!(
+            "{} estimate {:.0}s at {:.0}% confidence",
+            status.id,
+            estimate.secs,
+            estimate.confidence * 100.0
+        );
+        Some(estimate)
+    }
+
+    fn fold(&mut self, rate: f64, now: Instant) {
+        self.rates += 1;
+        self.ewma = Some(match self.ewma {
+            None => (rate, 0.0, now),
+            Some((mean, variance, at)) => {
+                // Time based, so that the smoothing does not depend on how
+                // often the battery is read.
+                let tau = self.window.as_secs_f64() / 5.0;
+                let alpha = 1.0 - (-now.duration_since(at).as_secs_f64() / tau).exp();
+                let diff = rate - mean;
+                let mean = mean + alpha * diff;
+                let variance = (1.0 - alpha) * (variance + alpha * diff * diff);
+                (mean, variance, now)
+            }
+        });
+    }
+
+    /// The smoothed power draw and its confidence: lower the more the draw
+    /// varies, and growing over the first readings.
+    fn smoothed(&self) -> Option<(f64, f64)> {
+        let (mean, variance, _) = self.ewma?;
+        let variation = variance.sqrt() / mean;
+        let warmup = (self.rates as f64 / 5.0).min(1.0);
+        Some((mean, warmup / (1.0 + 2.0 * variation)))
+    }
+
+    /// The slope of the charge level over the window and its confidence:
+    /// how well a line fits, times how much of the window it covers.
+    fn regression(&self) -> Option<(f64, f64)> {
+        if self.points.len() < MIN_POINTS {
+            return None;
+        }
+        let start = self.points.front()?.at;
+        let xs: Vec<_> = self
+            .points
+            .iter()
+            .map(|p| p.at.duration_since(start).as_secs_f64())
+            .collect();
+        let n = xs.len() as f64;
+        let mean_x = xs.iter().sum::<f64>() / n;
+        let mean_y = self.points.iter().map(|p| p.level).sum::<f64>() / n;
+        let (mut sxx, mut sxy, mut syy) = (0.0, 0.0, 0.0);
+        for (x, p) in xs.iter().zip(&self.points) {
+            let (dx, dy) = (x - mean_x, p.level - mean_y);
+            sxx += dx * dx;
+            sxy += dx * dy;
+            syy += dy * dy;
+        }
+        if sxx <= 0.0 || syy <= 0.0 {
+            return None;
+        }
+        let slope = sxy / sxx;
+        let rate = if self.discharging == Some(true) {
+            -slope
+        } else {
+            slope
+        };
+        if rate <= 0.0 {
+            return None;
+        }
+        let fit = sxy * sxy / (sxx * syy);
+        let span = xs.last()? / self.window.as_secs_f64();
+        Some((rate, fit * span.min(1.0)))
+    }
+
+    /// Whether the firmware estimates over the window disagree about when
+    /// the battery will be empty or full. Good estimates all point at about
+    /// the same time.
+    pub fn firmware_noisy(&self) -> bool {
+        let start = match self.points.front() {
+            Some(p) => p.at,
+            None => return false,
+        };
+        let ends: Vec<_> = self
+            .points
+            .iter()
+            .filter_map(|p| Some(p.at.duration_since(start).as_secs_f64() + p.firmware?))
+            .collect();
+        let last = match self.points.back().and_then(|p| p.firmware) {
+            Some(last) if ends.len() >= MIN_POINTS => last,
+            _ => return false,
+        };
+        let min = ends.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = ends.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        (max - min) / last > MAX_FIRMWARE_SPREAD
+    }
+
+    /// Replaces the time to empty or to full of `status` with the estimate
+    /// when the config asks for it and the estimate is confident enough.
+    pub fn apply(&mut self, config: &EstimatorConfig, status: &mut BatteryStatus, now: Instant) {
+        if config.mode == EstimatorMode::Firmware {
+            return;
+        }
+        let estimate = self
+            .update(status, now)
+            .filter(|e| e.confidence >= f64::from(config.confidence()));
+        if status.state == battery::State::Full {
+            return;
+        }
+        let discharging = monitor::is_discharging(status.state);
+        let firmware = match discharging {
+            true => &mut status.time_to_empty,
+            false => &mut status.time_to_full,
+        };
+        let replace = match config.mode {
+            EstimatorMode::Always => true,
+            _ => firmware.is_none() || self.firmware_noisy(),
+        };
+        if let Some(estimate) = estimate.filter(|_| replace) {
+            *firmware = Some(Time::new::<second>(estimate.secs as f32));
+            status.estimate_confidence = Some(estimate.confidence as f32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use battery::units::{Energy, Power};
+
+    use super::*;
+
+    const FULL: f32 = 50.0;
+
+    /// A steady 10 W discharge read every 30s: seconds, energy in Wh, power
+    /// in W and the firmware time to empty in seconds.
+    const STEADY: &str = "
+        0 30.00 9.79 10800
+        30 29.92 9.68 10771
+        60 29.84 9.92 10742
+        90 29.76 9.72 10712
+        120 29.67 9.65 10683
+        150 29.59 9.92 10654
+        180 29.51 10.33 10624
+        210 29.42 10.24 10593
+        240 29.34 10.21 10562
+        270 29.25 9.78 10532
+        300 29.17 10.03 10502
+        330 29.09 9.82 10472
+        360 29.01 9.74 10443
+        390 28.93 9.68 10413
+        420 28.85 9.77 10384
+        450 28.76 10.34 10355
+        480 28.68 10.26 10324
+        510 28.59 10.25 10293
+        540 28.51 10.24 10263
+        570 28.42 9.75 10232
+        600 28.34 9.85 10203
+    ";
+
+    /// A load switching between about 6 W and 19 W, averaging 9.7 W, with
+    /// firmware estimates following the momentary draw.
+    const BURSTY: &str = "
+        0 40.00 18.77 7672
+        30 39.84 19.09 7514
+        60 39.68 5.69 25096
+        90 39.64 5.42 26330
+        120 39.59 5.75 24806
+        150 39.54 5.72 24877
+        180 39.50 18.95 7503
+        210 39.34 5.75 24610
+        240 39.29 18.74 7550
+        270 39.13 5.77 24423
+        300 39.09 5.55 25370
+        330 39.04 18.73 7504
+        360 38.88 5.73 24438
+        390 38.84 5.87 23803
+        420 38.79 6.02 23178
+        450 38.74 18.54 7523
+        480 38.58 5.76 24106
+        510 38.53 5.92 23420
+        540 38.49 5.54 25015
+        570 38.44 6.00 23072
+        600 38.39 19.17 7211
+    ";
+
+    fn readings(trace: &str, state: battery::State) -> Vec<(u64, BatteryStatus)> {
+        trace
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let fields: Vec<f32> = line.split(' ').map(|f| f.parse().unwrap()).collect();
+                let firmware = Some(Time::new::<second>(fields[3]));
+                let (time_to_full, time_to_empty) = match state {
+                    battery::State::Charging => (firmware, None),
+                    _ => (None, firmware),
+                };
+                let status = BatteryStatus {
+                    time_to_full,
+                    time_to_empty,
+                    energy: Some(Energy::new::<watt_hour>(fields[1])),
+                    energy_full: Some(Energy::new::<watt_hour>(FULL)),
+                    energy_rate: Some(Power::new::<watt>(fields[2])),
+                    ..BatteryStatus::new("BAT0".to_string(), state, fields[1] / FULL)
+                };
+                (fields[0] as u64, status)
+            })
+            .collect()
+    }
+
+    /// Feeds `trace` to `estimator` and returns the last estimate.
+    fn feed(estimator: &mut Estimator, trace: &str, start: Instant) -> Option<Estimate> {
+        readings(trace, battery::State::Discharging)
+            .into_iter()
+            .map(|(secs, status)| estimator.update(&status, start + Duration::from_secs(secs)))
+            .last()
+            .flatten()
+    }
+
+    fn assert_near(secs: f64, expected: f64) {
+        let error = (secs - expected).abs() / expected;
+        assert!(error < 0.05, "{} is not within 5% of {}", secs, expected);
+    }
+
+    #[test]
+    fn steady_discharge() {
+        let mut estimator = Estimator::new(Duration::from_secs(600));
+        let estimate = feed(&mut estimator, STEADY, Instant::now()).unwrap();
+        // 28.34 Wh left at 10 W.
+        assert_near(estimate.secs, 28.34 / 10.0 * 3600.0);
+        assert!(estimate.confidence > 0.8, "{:?}", estimate);
+        assert!(!estimator.firmware_noisy());
+    }
+
+    #[test]
+    fn noisy_firmware_is_replaced() {
+        let config = EstimatorConfig::default();
+        let mut estimator = Estimator::new(config.window);
+        let start = Instant::now();
+        let mut readings = readings(BURSTY, battery::State::Discharging);
+        let (last, mut status) = readings.pop().unwrap();
+        for (secs, status) in &readings {
+            estimator.update(status, start + Duration::from_secs(*secs));
+        }
+        estimator.apply(&config, &mut status, start + Duration::from_secs(last));
+        assert!(estimator.firmware_noisy());
+        // 38.39 Wh left at 9.66 W on average, not the 7211s of the firmware.
+        let secs = status.time_to_empty.unwrap().get::<second>();
+        assert_near(f64::from(secs), 38.39 / 9.66 * 3600.0);
+        assert!(status.estimate_confidence.is_some());
+    }
+
+    #[test]
+    fn steady_firmware_is_kept() {
+        let config = EstimatorConfig::default();
+        let mut estimator = Estimator::new(config.window);
+        let start = Instant::now();
+        let mut status = None;
+        for (secs, mut reading) in readings(STEADY, battery::State::Discharging) {
+            estimator.apply(&config, &mut reading, start + Duration::from_secs(secs));
+            status = Some(reading);
+        }
+        let status = status.unwrap();
+        assert_eq!(status.time_to_empty, Some(Time::new::<second>(10203.0)));
+        assert_eq!(status.estimate_confidence, None);
+    }
+
+    #[test]
+    fn resets_on_charge_switch() {
+        let mut estimator = Estimator::new(Duration::from_secs(600));
+        let start = Instant::now();
+        feed(&mut estimator, STEADY, start).unwrap();
+        let (_, charging) = readings(STEADY, battery::State::Charging).pop().unwrap();
+        let estimate = estimator
+            .update(&charging, start + Duration::from_secs(630))
+            .unwrap();
+        // One reading into the charge: the time to full at the reported
+        // rate, with little confidence.
+        assert_near(estimate.secs, (FULL as f64 - 28.34) / 9.85 * 3600.0);
+        assert!(estimate.confidence < 0.5, "{:?}", estimate);
+        assert!(!estimator.firmware_noisy());
+    }
+
+    #[test]
+    fn confidence_threshold() {
+        let mut config = EstimatorConfig {
+            mode: EstimatorMode::Always,
+            ..EstimatorConfig::default()
+        };
+        let start = Instant::now();
+        let readings = readings(STEADY, battery::State::Discharging);
+
+        // Too few readings for a confident estimate: the firmware value stays.
+        let mut estimator = Estimator::new(config.window);
+        let mut early = readings[1].1.clone();
+        estimator.update(&readings[0].1, start);
+        estimator.apply(&config, &mut early, start + Duration::from_secs(30));
+        assert_eq!(early.time_to_empty, readings[1].1.time_to_empty);
+        assert_eq!(early.estimate_confidence, None);
+
+        for min_confidence in [50.0, 100.0] {
+            config.min_confidence = min_confidence;
+            let mut estimator = Estimator::new(config.window);
+            let mut status = None;
+            for (secs, mut reading) in readings.clone() {
+                estimator.apply(&config, &mut reading, start + Duration::from_secs(secs));
+                status = Some(reading);
+            }
+            let replaced = status.unwrap().estimate_confidence.is_some();
+            assert_eq!(replaced, min_confidence < 100.0);
+        }
+    }
+}
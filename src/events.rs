@@ -0,0 +1,103 @@
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::mpsc::Sender;
+use std::thread;
+
+use log::{debug, error, trace};
+
+use crate::control::Request;
+
+/// Wakes the main loop before its poll interval elapses. Anything holding a
+/// `Sender<Event>` can drive the loop, which is how tests replace the kernel.
+#[derive(Debug)]
+pub enum Event {
+    /// A `power_supply` device reported a change.
+    PowerSupply,
+    /// The process was asked to exit.
+    Stop,
+    /// A request from the control socket, answered with text on the sender.
+    Control(Request, Sender<String>),
+}
+
+/// Kernel uevents, as opposed to the ones re-broadcast by udev.
+const KERNEL_GROUP: u32 = 1;
+
+fn open_uevent_socket() -> io::Result<OwnedFd> {
+    // SAFETY: plain socket(2) call, the result is checked before use.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            libc::NETLINK_KOBJECT_UEVENT,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` is a freshly created descriptor nothing else owns.
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    // SAFETY: sockaddr_nl is plain data, all zeroes is a valid value.
+    let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    addr.nl_groups = KERNEL_GROUP;
+    // SAFETY: `addr` outlives the call and the length matches its type.
+    let res = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+/// A uevent is `action@devpath` followed by `KEY=value` pairs, all separated
+/// by NUL bytes.
+fn is_power_supply_event(msg: &[u8]) -> bool {
+    msg.split(|b| *b == 0)
+        .skip(1)
+        .any(|field| field == b"SUBSYSTEM=power_supply")
+}
+
+/// Subscribes to kernel `power_supply` uevents and forwards each one as
+/// [`Event::PowerSupply`] from a background thread. Fails if the netlink
+/// socket cannot be opened, e.g. in a restricted container.
+pub fn listen_uevents(tx: Sender<Event>) -> io::Result<()> {
+    let socket = open_uevent_socket()?;
+    thread::spawn(move || {
+        let mut buf = [0u8; 8192];
+        loop {
+            // SAFETY: `buf` is valid for writes of its whole length.
+            let len = unsafe {
+                libc::recv(
+                    socket.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if len < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("failed to receive uevent: {}", e);
+                return;
+            }
+            let msg = &buf[..len as usize];
+            if !is_power_supply_event(msg) {
+                continue;
+            }
+            trace!("uevent {:?}", String::from_utf8_lossy(msg));
+            if tx.send(Event::PowerSupply).is_err() {
+                debug!("event loop gone, stop listening for uevents");
+                return;
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,57 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::state;
+
+/// `health.json` in the state directory.
+pub fn default_path() -> Option<PathBuf> {
+    Some(state::dir()?.join("health.json"))
+}
+
+/// Health and cycle count crossings already notified for one battery.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Marks {
+    /// Health is below `notifications.health.health`.
+    pub below: bool,
+    /// The highest of `notifications.health.cycles` passed.
+    pub cycles: u32,
+}
+
+/// [`Marks`] of every battery seen, by id, saved to a file on every change
+/// so that a crossing is notified once and not again after a restart.
+pub struct HealthState {
+    path: Option<PathBuf>,
+    batteries: BTreeMap<String, Marks>,
+}
+
+impl HealthState {
+    /// Loads the marks saved at `path`. Without a path nothing is saved and
+    /// crossings are notified again after a restart.
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let batteries = path
+            .as_deref()
+            .and_then(|path| state::load(path, "battery health state"))
+            .unwrap_or_default();
+        HealthState { path, batteries }
+    }
+
+    pub fn marks(&self, id: &str) -> Marks {
+        self.batteries.get(id).cloned().unwrap_or_default()
+    }
+
+    /// Stores the marks of `id`, saving them if they changed.
+    pub fn set(&mut self, id: &str, marks: Marks) {
+        if self.batteries.get(id) == Some(&marks) {
+            return;
+        }
+        debug!("battery {} health marks now {:?}", id, marks);
+        self.batteries.insert(id.to_string(), marks);
+        if let Some(path) = &self.path {
+            state::save(path, &self.batteries, "battery health state");
+        }
+    }
+}
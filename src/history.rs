@@ -0,0 +1,200 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use battery::units::energy::watt_hour;
+use battery::units::power::watt;
+use log::{debug, warn};
+use rusqlite::{params, Connection};
+
+use crate::config::HistoryConfig;
+use crate::payload::unix_time;
+use crate::publisher::Publisher;
+use crate::source::BatteryStatus;
+
+/// How often old samples are downsampled and expired.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS samples (
+    time INTEGER NOT NULL,
+    battery TEXT NOT NULL,
+    state TEXT NOT NULL,
+    charge REAL NOT NULL,
+    energy REAL,
+    energy_full REAL,
+    energy_full_design REAL,
+    power REAL,
+    cycle_count INTEGER,
+    downsampled INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS samples_battery_time ON samples (battery, time);
+";
+
+/// `$XDG_DATA_HOME/battery-notifier/history.sqlite`, falling back to
+/// `~/.local/share` when `XDG_DATA_HOME` is unset.
+pub fn default_path() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_DATA_HOME").filter(|v| !v.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".local/share"),
+    };
+    Some(base.join("battery-notifier").join("history.sqlite"))
+}
+
+/// Opens the history database at `path`, creating it and its directory if
+/// needed.
+pub fn open(path: &Path) -> rusqlite::Result<Connection> {
+    if let Some(dir) = path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    let connection = Connection::open(path)?;
+    connection.execute_batch(&format!("PRAGMA journal_mode = WAL;{}", SCHEMA))?;
+    Ok(connection)
+}
+
+/// A stored reading of one battery. Downsampled rows hold the averages of
+/// their bucket and the last state seen in it.
+#[derive(Debug, Clone)]
+pub struct Sample {
+    /// Unix time in seconds.
+    pub time: i64,
+    pub battery: String,
+    pub state: String,
+    pub charge: f64,
+    /// Watt-hours.
+    pub energy: Option<f64>,
+    pub energy_full: Option<f64>,
+    pub energy_full_design: Option<f64>,
+    /// Watts drawn or charged.
+    pub power: Option<f64>,
+    pub cycle_count: Option<i64>,
+}
+
+/// Every sample taken at or after `since`, ordered by battery and time.
+pub fn samples(connection: &Connection, since: i64) -> rusqlite::Result<Vec<Sample>> {
+    let mut statement = connection.prepare(
+        "SELECT time, battery, state, charge, energy, energy_full, energy_full_design,
+             power, cycle_count
+         FROM samples WHERE time >= ?1 ORDER BY battery, time",
+    )?;
+    let rows = statement.query_map([since], |row| {
+        Ok(Sample {
+            time: row.get(0)?,
+            battery: row.get(1)?,
+            state: row.get(2)?,
+            charge: row.get(3)?,
+            energy: row.get(4)?,
+            energy_full: row.get(5)?,
+            energy_full_design: row.get(6)?,
+            power: row.get(7)?,
+            cycle_count: row.get(8)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Stores every reading of every battery. Samples older than `keep_raw` are
+/// averaged into `downsample` buckets, and those older than `keep` deleted.
+pub struct History {
+    connection: Connection,
+    keep_raw: Duration,
+    downsample: Duration,
+    keep: Duration,
+    last_maintenance: Option<Instant>,
+}
+
+impl History {
+    pub fn open(path: &Path, config: &HistoryConfig) -> rusqlite::Result<Self> {
+        Ok(History {
+            connection: open(path)?,
+            keep_raw: config.keep_raw,
+            downsample: config.downsample,
+            keep: config.keep,
+            last_maintenance: None,
+        })
+    }
+
+    fn insert(&mut self, time: i64, batteries: &[&BatteryStatus]) -> rusqlite::Result<()> {
+        let tx = self.connection.transaction()?;
+        {
+            let mut statement = tx.prepare_cached(
+                "INSERT INTO samples
+                 (time, battery, state, charge, energy, energy_full, energy_full_design,
+                     power, cycle_count)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            )?;
+            for status in batteries {
+                statement.execute(params![
+                    time,
+                    status.id,
+                    status.state.to_string(),
+                    status.charge,
+                    status.energy.map(|e| e.get::<watt_hour>()),
+                    status.energy_full.map(|e| e.get::<watt_hour>()),
+                    status.energy_full_design.map(|e| e.get::<watt_hour>()),
+                    status.energy_rate.map(|p| p.get::<watt>()),
+                    status.cycle_count,
+                ])?;
+            }
+        }
+        tx.commit()
+    }
+
+    /// Replaces the raw samples older than `keep_raw` with one row per
+    /// battery and bucket, and drops everything older than `keep`.
+    fn maintain(&mut self, time: i64) -> rusqlite::Result<()> {
+        let bucket = self.downsample.as_secs().max(1) as i64;
+        // Aligned so that no bucket is split between two runs.
+        let cutoff = (time - self.keep_raw.as_secs() as i64).div_euclid(bucket) * bucket;
+        let expiry = time - self.keep.as_secs() as i64;
+        let tx = self.connection.transaction()?;
+        let downsampled = tx.execute(
+            "INSERT INTO samples
+             (time, battery, state, charge, energy, energy_full, energy_full_design,
+                 power, cycle_count, downsampled)
+             SELECT time / ?1 * ?1 AS bucket, battery,
+                 (SELECT s.state FROM samples s
+                  WHERE s.battery = samples.battery AND s.downsampled = 0
+                      AND s.time / ?1 = samples.time / ?1
+                  ORDER BY s.time DESC LIMIT 1),
+                 avg(charge), avg(energy), avg(energy_full), avg(energy_full_design),
+                 avg(power), max(cycle_count), 1
+             FROM samples
+             WHERE downsampled = 0 AND time < ?2
+             GROUP BY battery, bucket",
+            params![bucket, cutoff],
+        )?;
+        tx.execute(
+            "DELETE FROM samples WHERE downsampled = 0 AND time < ?1",
+            [cutoff],
+        )?;
+        let expired = tx.execute("DELETE FROM samples WHERE time < ?1", [expiry])?;
+        tx.commit()?;
+        if downsampled > 0 || expired > 0 {
+            debug!(
+                "downsampled history into {} rows, expired {}",
+                downsampled, expired
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Publisher for History {
+    fn publish(&mut self, batteries: &[&BatteryStatus], _: Option<&BatteryStatus>, now: Instant) {
+        let time = unix_time() as i64;
+        if let Err(e) = self.insert(time, batteries) {
+            warn!("failed to store battery history: {}", e);
+        }
+        let due = self
+            .last_maintenance
+            .is_none_or(|last| now >= last + MAINTENANCE_INTERVAL);
+        if due {
+            if let Err(e) = self.maintain(time) {
+                warn!("failed to downsample battery history: {}", e);
+            }
+            self.last_maintenance = Some(now);
+        }
+    }
+}
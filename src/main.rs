@@ -1,127 +1,579 @@
+mod action;
+mod bar;
+mod cli;
+mod config;
+mod control;
+mod dbus;
+mod drain;
+mod estimator;
+mod events;
+mod health;
+mod history;
+mod metrics;
+mod monitor;
+mod mqtt;
+mod notification;
+mod notifier;
+mod payload;
+mod publisher;
+mod report;
+mod scheduler;
+mod source;
+mod state;
+mod sysfs;
+mod webhook;
+
 use std::error::Error;
-use std::fmt::{Debug, Display};
-use std::sync::atomic;
-use std::sync::Arc;
-use std::thread;
-use std::time;
-
-use log::{debug, error, info};
-use notify_rust::Notification;
-
-const NOTIFICATION_TIMEOUT: i32 = 3000;
-const LOOP_WAIT_TIME: time::Duration = time::Duration::from_secs(1);
-const CRITICAL_CHARGE: f32 = 0.15;
-
-#[derive(Debug)]
-struct BatteryStatus {
-    state: battery::State,
-    time_to_full: Option<battery::units::Time>,
-    charge: f32,
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+use battery::units::power::watt;
+use battery::units::thermodynamic_temperature::degree_celsius;
+use clap::Parser;
+use log::{debug, error, info, warn};
+
+use bar::Bar;
+use cli::{
+    Cli, Command, CtlCommand, NotificationKind, ReportFormat, RunArgs, SourceArgs, SourceKind,
+};
+use config::{Config, SinkConfig};
+use control::Request;
+use dbus::DbusService;
+use drain::Drain;
+use events::Event;
+use history::History;
+use metrics::MetricsExporter;
+use monitor::{Monitor, Snooze};
+use mqtt::MqttPublisher;
+use notifier::{Fanout, Notifier};
+use publisher::Publisher;
+use scheduler::Scheduler;
+use source::{BatterySource, BatteryStatus, ManagerSource, ScriptedSource};
+use sysfs::SysfsSource;
+
+fn open_source(args: &SourceArgs) -> Result<Box<dyn BatterySource>, Box<dyn Error>> {
+    if let Some(path) = &args.script {
+        info!("replaying battery script {:?}", path);
+        return Ok(Box::new(ScriptedSource::from_file(path)?));
+    }
+    match args.kind {
+        SourceKind::Battery => Ok(Box::new(ManagerSource::new()?)),
+        SourceKind::Sysfs => {
+            info!("reading batteries from {:?}", args.sysfs_root);
+            Ok(Box::new(SysfsSource::new(&args.sysfs_root)))
+        }
+    }
 }
 
-#[derive(Debug)]
-enum BatteryError {
-    FailedToGetState,
-    LibError(battery::Error),
+/// Builds the alert sinks and the publishers fed with every reading.
+fn outputs<'a>(
+    config: &'a Config,
+    args: &RunArgs,
+    events: &Sender<Event>,
+) -> (Fanout, Vec<Box<dyn Publisher + 'a>>) {
+    let mut fanout = Fanout::from_config(config);
+    let mut publishers: Vec<Box<dyn Publisher + 'a>> = Vec::new();
+    if let Some(protocol) = args.bar {
+        publishers.push(Box::new(Bar::new(config, protocol, events.clone())));
+    }
+    if config.mqtt.enabled {
+        let mqtt = MqttPublisher::connect(&config.mqtt);
+        fanout.add("mqtt", mqtt.events());
+        publishers.push(Box::new(mqtt));
+    }
+    if config.dbus {
+        match DbusService::start() {
+            Ok(service) => {
+                info!("serving {} on the session bus", dbus::NAME);
+                fanout.add("dbus", service.signals());
+                publishers.push(Box::new(service));
+            }
+            Err(e) => warn!("failed to start the D-Bus service: {}", e),
+        }
+    }
+    if config.metrics.enabled {
+        match MetricsExporter::start(config.metrics.listen) {
+            Ok(exporter) => {
+                info!(
+                    "serving metrics on http://{}/metrics",
+                    config.metrics.listen
+                );
+                fanout.count(exporter.metrics());
+                publishers.push(Box::new(exporter));
+            }
+            Err(e) => warn!(
+                "failed to serve metrics on {}: {}",
+                config.metrics.listen, e
+            ),
+        }
+    }
+    if config.history.enabled {
+        match config.history_path() {
+            Some(path) => match History::open(&path, &config.history) {
+                Ok(history) => {
+                    info!("recording battery history to {:?}", path);
+                    publishers.push(Box::new(history));
+                }
+                Err(e) => warn!("failed to open battery history {:?}: {}", path, e),
+            },
+            None => warn!("no history.path set and no home directory to default to"),
+        }
+    }
+    (fanout, publishers)
+}
+
+fn publish(publishers: &mut [Box<dyn Publisher + '_>], monitor: &Monitor, now: Instant) {
+    let batteries: Vec<_> = monitor.batteries().collect();
+    for publisher in publishers {
+        publisher.publish(&batteries, monitor.system(), now);
+    }
+}
+
+fn run(
+    config: &Config,
+    args: &RunArgs,
+    source: &mut dyn BatterySource,
+) -> Result<(), Box<dyn Error>> {
+    if args.bar.is_some() && config.sinks.iter().any(|s| matches!(s, SinkConfig::Stdout)) {
+        return Err("the stdout sink cannot be combined with --bar".into());
+    }
+    let (tx, rx) = mpsc::channel();
+    let (fanout, mut publishers) = outputs(config, args, &tx);
+    let health_path = match config.notifications.health.enabled {
+        true => health::default_path(),
+        false => None,
+    };
+    let drain_path = match config.notifications.drain.enabled {
+        true => drain::default_path(),
+        false => None,
+    };
+    let mut monitor = Monitor::new(config, Box::new(fanout), health_path, drain_path);
+    let batteries = source.batteries()?;
+    debug!("got initial battery states {:?}", batteries);
+    let now = Instant::now();
+    monitor.update(batteries, now);
+    publish(&mut publishers, &monitor, now);
+    if args.once {
+        return Ok(());
+    }
+
+    let stop = tx.clone();
+    ctrlc::set_handler(move || {
+        let _ = stop.send(Event::Stop);
+    })
+    .expect("failed to set ctrl-c trap");
+    let _control = match config.control {
+        true => match control::listen(&config.control_socket(), tx.clone()) {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                warn!("failed to open the control socket: {}", e);
+                None
+            }
+        },
+        false => None,
+    };
+    let mut uevents = false;
+    if config.uevents {
+        match events::listen_uevents(tx) {
+            Ok(()) => {
+                info!("listening for power_supply uevents");
+                uevents = true;
+            }
+            Err(e) => warn!("failed to listen for uevents, polling instead: {}", e),
+        }
+    }
+    // Uevents already report changes as they happen, leaving polling as a
+    // slow fallback.
+    let fixed = match args.interval {
+        Some(interval) => Some(interval),
+        None if uevents => Some(config.fallback_interval),
+        None if config.polling.adaptive => None,
+        None => Some(config.interval),
+    };
+    match fixed {
+        Some(interval) => info!("start fetching state every {:?}", interval),
+        None => info!(
+            "start fetching state every {:?} to {:?}",
+            config.polling.min_interval, config.polling.max_interval
+        ),
+    }
+    let mut scheduler = Scheduler::new(config, fixed);
+    event_loop(&mut monitor, source, &rx, &mut scheduler, &mut publishers)
 }
 
-impl Display for BatteryError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            BatteryError::FailedToGetState => write!(f, "failed to get battery state"),
-            BatteryError::LibError(e) => write!(f, "{:?}", e),
+/// Reads the batteries on every event, and at least as often as the
+/// scheduler asks for.
+fn event_loop(
+    monitor: &mut Monitor,
+    source: &mut dyn BatterySource,
+    events: &mpsc::Receiver<Event>,
+    scheduler: &mut Scheduler,
+    publishers: &mut [Box<dyn Publisher + '_>],
+) -> Result<(), Box<dyn Error>> {
+    'events: loop {
+        let interval = scheduler.interval(monitor.system());
+        let now = Instant::now();
+        // A deadline already past, e.g. a refresh missed because reading the
+        // batteries failed, would otherwise spin the loop.
+        let deadline = monitor
+            .next_deadline(now)
+            .into_iter()
+            .chain(publishers.iter().filter_map(|p| p.next_deadline()))
+            .filter(|deadline| *deadline > now)
+            .min();
+        let timeout = match deadline {
+            Some(deadline) => deadline.duration_since(now).min(interval),
+            None => interval,
+        };
+        match events.recv_timeout(timeout) {
+            Ok(Event::Stop) | Err(RecvTimeoutError::Disconnected) => break,
+            Ok(Event::Control(request, reply)) => {
+                let _ = reply.send(control(monitor, request));
+                continue;
+            }
+            Ok(Event::PowerSupply) => {
+                // A plug event usually comes with a burst of uevents.
+                for event in events.try_iter() {
+                    match event {
+                        Event::Stop => break 'events,
+                        Event::Control(request, reply) => {
+                            let _ = reply.send(control(monitor, request));
+                        }
+                        Event::PowerSupply => {}
+                    }
+                }
+                debug!("power_supply changed");
+            }
+            Err(RecvTimeoutError::Timeout) => {}
         }
+
+        match source.batteries() {
+            Ok(new_batteries) => {
+                let now = Instant::now();
+                monitor.update(new_batteries, now);
+                publish(publishers, monitor, now);
+            }
+            Err(e) => {
+                error!("{:?}", e);
+                for publisher in publishers.iter_mut() {
+                    publisher.source_error(&e);
+                }
+            }
+        };
     }
+    info!("ctrl-c catched. exiting...");
+    Ok(())
+}
+
+fn format_secs(duration: Duration) -> String {
+    humantime::format_duration(Duration::from_secs(duration.as_secs())).to_string()
 }
 
-impl Error for BatteryError {}
+/// Applies a control socket request and returns the answer for the client.
+fn control(monitor: &mut Monitor, request: Request) -> String {
+    let now = Instant::now();
+    match request {
+        Request::Snooze(duration) => {
+            monitor.snooze(Snooze::Until(now + duration));
+            format!("alerts snoozed for {}\n", format_secs(duration))
+        }
+        Request::Pause => {
+            monitor.snooze(Snooze::UntilStateChange);
+            "alerts paused until a battery changes state\n".to_string()
+        }
+        Request::Resume => {
+            monitor.snooze(Snooze::Off);
+            "alerts resumed\n".to_string()
+        }
+        Request::Status => {
+            let mut reply = String::new();
+            for status in monitor.batteries().chain(monitor.system()) {
+                reply.push_str(&notification::status_line(status));
+                reply.push('\n');
+            }
+            reply.push_str(&match monitor.snoozed(now) {
+                Snooze::Off => "alerts: on\n".to_string(),
+                Snooze::Until(until) => {
+                    format!("alerts: snoozed for another {}\n", format_secs(until - now))
+                }
+                Snooze::UntilStateChange => {
+                    "alerts: paused until a battery changes state\n".to_string()
+                }
+            });
+            reply
+        }
+    }
+}
 
-impl From<battery::Error> for BatteryError {
-    fn from(e: battery::Error) -> Self {
-        BatteryError::LibError(e)
+fn ctl(config: &Config, command: CtlCommand) -> Result<(), Box<dyn Error>> {
+    let request = match command {
+        CtlCommand::Snooze { duration } => Request::Snooze(duration),
+        CtlCommand::Pause => Request::Pause,
+        CtlCommand::Resume => Request::Resume,
+        CtlCommand::Status => Request::Status,
+    };
+    let reply = match control::send(&config.control_socket(), request) {
+        Ok(reply) => reply,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    if let Some(e) = reply.strip_prefix("error:") {
+        eprintln!("{}", e.trim());
+        process::exit(1);
     }
+    print!("{}", reply);
+    Ok(())
 }
 
-fn get_battery_state() -> Result<BatteryStatus, BatteryError> {
-    let manager = battery::Manager::new()?;
-    if let Some(bat) = manager.batteries()?.take(1).next().transpose()? {
-        Ok(BatteryStatus {
-            state: bat.state(),
-            time_to_full: bat.time_to_full(),
-            charge: bat.state_of_charge().value,
-        })
-    } else {
-        Err(BatteryError::FailedToGetState)
+fn print_status(source: &mut dyn BatterySource) -> Result<(), Box<dyn Error>> {
+    let batteries = source.batteries()?;
+    let system = BatteryStatus::aggregate(&batteries);
+    for status in batteries.iter().chain(system.iter()) {
+        println!("{}", notification::status_line(status));
     }
+    Ok(())
 }
 
-fn get_battery_state_changed_notif(
-    state: battery::State,
-    time_to_charge: Option<battery::units::Time>,
-) -> Notification {
-    let mut n = Notification::new()
-        .summary(&format!("Battery state - {:?}", state))
-        .timeout(NOTIFICATION_TIMEOUT)
-        .finalize();
-    if let Some(ttc) = time_to_charge {
-        let duration =
-            time::Duration::from_nanos(ttc.get::<battery::units::time::nanosecond>() as u64);
-        return n
-            .body(&format!("{}m", (duration.as_secs() / 60) % 60))
-            .finalize();
-    }
-
-    n
+fn report(
+    config: &Config,
+    format: ReportFormat,
+    since: Duration,
+    output: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let path = config.history_path().ok_or("no history.path set")?;
+    if !path.exists() {
+        return Err(format!(
+            "no history recorded at {}, enable [history] in the config",
+            path.display()
+        )
+        .into());
+    }
+    let connection = history::open(&path)?;
+    let since = payload::unix_time().saturating_sub(since.as_secs()) as i64;
+    let samples = history::samples(&connection, since)?;
+    let report = report::render(&samples, config.history.downsample, format);
+    match output {
+        Some(output) => fs::write(output, report)?,
+        None => print!("{}", report),
+    }
+    Ok(())
 }
 
-fn get_battery_low_notif(val: f32) -> Notification {
-    Notification::new()
-        .summary("Battery charge is critically low")
-        .body(&format!("charge - {}%", val * 100_f32))
-        .timeout(NOTIFICATION_TIMEOUT)
-        .finalize()
+fn test_notification(
+    config: &Config,
+    kind: NotificationKind,
+    name: Option<&str>,
+    source: &mut dyn BatterySource,
+) -> Result<(), Box<dyn Error>> {
+    let batteries = source.batteries()?;
+    let system = BatteryStatus::aggregate(&batteries).ok_or("no battery found")?;
+    let alert = match kind {
+        NotificationKind::StateChanged => notification::state_changed(config, &system, false),
+        NotificationKind::Charged => notification::charged(config, &system),
+        NotificationKind::Health => {
+            let reason = format!(
+                "Health dropped below {}%",
+                config.notifications.health.health
+            );
+            // The most worn battery, when the sum of them has no health.
+            let status = batteries
+                .iter()
+                .filter_map(|b| Some((b, b.state_of_health?)))
+                .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map(|(b, _)| b)
+                .filter(|_| system.state_of_health.is_none())
+                .unwrap_or(&system);
+            notification::health(config, status, reason, false)
+        }
+        NotificationKind::Drain => {
+            // The current draw, or a made up one, at the configured factor.
+            let power = system
+                .energy_rate
+                .map(|p| f64::from(p.get::<watt>()))
+                .filter(|p| *p > 0.0)
+                .unwrap_or(20.0);
+            let factor = f64::from(config.notifications.drain.factor);
+            let drain = Drain {
+                power,
+                typical: power / factor,
+            };
+            notification::drain(config, &system, &drain)
+        }
+        NotificationKind::Threshold => {
+            let threshold = match name {
+                Some(name) => config.thresholds.iter().find(|t| t.name == name),
+                None => config.thresholds.last(),
+            }
+            .ok_or("no such threshold")?;
+            notification::threshold(threshold, &system)
+        }
+        NotificationKind::Temperature => {
+            let threshold = match name {
+                Some(name) => config
+                    .temperature_thresholds
+                    .iter()
+                    .find(|t| t.name == name),
+                None => config.temperature_thresholds.last(),
+            }
+            .ok_or("no such temperature threshold")?;
+            // Shown at the threshold when the battery reports no temperature.
+            let celsius = system
+                .temperature
+                .map(|t| t.get::<degree_celsius>())
+                .unwrap_or(threshold.celsius);
+            notification::temperature(threshold, &system, celsius)
+        }
+    };
+    match alert {
+        Some(alert) => Fanout::from_config(config).notify(&alert),
+        None => {
+            println!("{:?} notifications are disabled in the config", kind);
+            Ok(())
+        }
+    }
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
-    let t = time::Duration::from_secs(1);
-    println!("{:?}", t);
-    let mut state = get_battery_state()?;
-    debug!("got initial battery state");
-    let running = Arc::new(atomic::AtomicBool::new(true));
-    let running_clone = running.clone();
-    ctrlc::set_handler(move || running_clone.store(false, atomic::Ordering::Relaxed))
-        .expect("failed to set ctrl-c trap");
-    info!("start fetching state every {:?}", LOOP_WAIT_TIME);
-    let mut is_low_notified = false;
-    loop {
-        thread::sleep(LOOP_WAIT_TIME);
-        if !running.load(atomic::Ordering::Relaxed) {
-            info!("ctrl-c catched. exiting...");
-            break;
-        }
-
-        match get_battery_state() {
-            Ok(new_state) => {
-                if new_state.state != state.state {
-                    get_battery_state_changed_notif(new_state.state, new_state.time_to_full)
-                        .show()?;
-                    debug!("new battery state {:?}", new_state.state);
-                }
-                if new_state.charge <= CRITICAL_CHARGE {
-                    if !is_low_notified {
-                        get_battery_low_notif(new_state.charge).show()?;
-                        is_low_notified = true;
-                        debug!("charge is lower then 15% - {}", new_state.charge);
-                    }
-                } else {
-                    is_low_notified = false;
-                }
+    let cli = Cli::parse();
+    let config = match Config::load(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    debug!("loaded config {:?}", config);
+    match cli.command.unwrap_or(Command::Run(cli.run)) {
+        Command::Run(args) => run(&config, &args, open_source(&cli.source)?.as_mut()),
+        Command::Status => print_status(open_source(&cli.source)?.as_mut()),
+        Command::CheckConfig => {
+            println!("config is valid");
+            Ok(())
+        }
+        Command::Ctl { command } => ctl(&config, command),
+        Command::Report {
+            format,
+            since,
+            output,
+        } => report(&config, format, since, output.as_deref()),
+        Command::TestNotification { kind, name } => test_notification(
+            &config,
+            kind,
+            name.as_deref(),
+            open_source(&cli.source)?.as_mut(),
+        ),
+    }
+}
 
-                state = new_state;
-            }
-            Err(e) => error!("{:?}", e),
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use battery::State;
+
+    use super::*;
+    use source::BatteryError;
+
+    /// Counts its readings and stops the loop on the first one.
+    struct StopOnRead {
+        reads: usize,
+        events: Sender<Event>,
+    }
+
+    impl BatterySource for StopOnRead {
+        fn batteries(&mut self) -> Result<Vec<BatteryStatus>, BatteryError> {
+            self.reads += 1;
+            let _ = self.events.send(Event::Stop);
+            Ok(vec![BatteryStatus::new(
+                "BAT0".to_string(),
+                State::Discharging,
+                0.5,
+            )])
+        }
+    }
+
+    /// Runs the event loop with a poll interval long enough that only events
+    /// wake it, and returns how often it read the batteries.
+    fn run(send: impl FnOnce(&Sender<Event>)) -> usize {
+        let config = Config::default();
+        let (tx, rx) = mpsc::channel();
+        let mut source = StopOnRead {
+            reads: 0,
+            events: tx.clone(),
         };
+        let mut monitor = Monitor::new(&config, Box::new(Fanout::new()), None, None);
+        let mut scheduler = Scheduler::new(&config, Some(Duration::from_secs(3600)));
+        send(&tx);
+        event_loop(&mut monitor, &mut source, &rx, &mut scheduler, &mut []).unwrap();
+        source.reads
+    }
+
+    #[test]
+    fn power_supply_event_reads_batteries() {
+        let reads = run(|tx| {
+            tx.send(Event::PowerSupply).unwrap();
+            tx.send(Event::PowerSupply).unwrap();
+        });
+        // The burst is read once, and the read sends the stop.
+        assert_eq!(reads, 1);
+    }
+
+    #[test]
+    fn stop_exits_without_reading() {
+        let reads = run(|tx| tx.send(Event::Stop).unwrap());
+        assert_eq!(reads, 0);
+    }
+
+    /// Fails every reading.
+    struct Failing {
+        reads: usize,
+    }
+
+    impl BatterySource for Failing {
+        fn batteries(&mut self) -> Result<Vec<BatteryStatus>, BatteryError> {
+            self.reads += 1;
+            Err(BatteryError::FailedToGetState)
+        }
+    }
+
+    /// Wants a refresh that never comes, as MQTT does while readings fail.
+    struct Overdue(Instant);
+
+    impl Publisher for Overdue {
+        fn publish(&mut self, _: &[&BatteryStatus], _: Option<&BatteryStatus>, _: Instant) {}
+
+        fn next_deadline(&self) -> Option<Instant> {
+            Some(self.0)
+        }
+    }
+
+    #[test]
+    fn past_deadline_does_not_spin() {
+        let config = Config::default();
+        let (tx, rx) = mpsc::channel();
+        let mut source = Failing { reads: 0 };
+        let mut monitor = Monitor::new(&config, Box::new(Fanout::new()), None, None);
+        let mut scheduler = Scheduler::new(&config, Some(Duration::from_secs(3600)));
+        let mut publishers: Vec<Box<dyn Publisher>> = vec![Box::new(Overdue(Instant::now()))];
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            tx.send(Event::Stop).unwrap();
+        });
+        event_loop(
+            &mut monitor,
+            &mut source,
+            &rx,
+            &mut scheduler,
+            &mut publishers,
+        )
+        .unwrap();
+        stopper.join().unwrap();
+        assert_eq!(source.reads, 0);
     }
-    Ok(())
 }
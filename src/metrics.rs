@@ -0,0 +1,231 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use battery::units::electric_potential::volt;
+use battery::units::energy::watt_hour;
+use battery::units::power::watt;
+use battery::units::thermodynamic_temperature::degree_celsius;
+use log::debug;
+
+use crate::notifier::AlertKind;
+use crate::publisher::Publisher;
+use crate::source::{BatteryError, BatteryStatus};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Gauges of one battery, taken from its last reading.
+struct Gauges {
+    id: String,
+    charge: f32,
+    energy: Option<f32>,
+    energy_full: Option<f32>,
+    energy_full_design: Option<f32>,
+    power: Option<f32>,
+    voltage: Option<f32>,
+    temperature: Option<f32>,
+    cycle_count: Option<f32>,
+    health: Option<f32>,
+}
+
+impl From<&BatteryStatus> for Gauges {
+    fn from(status: &BatteryStatus) -> Self {
+        Gauges {
+            id: status.id.clone(),
+            charge: status.charge,
+            energy: status.energy.map(|e| e.get::<watt_hour>()),
+            energy_full: status.energy_full.map(|e| e.get::<watt_hour>()),
+            energy_full_design: status.energy_full_design.map(|e| e.get::<watt_hour>()),
+            power: status.energy_rate.map(|p| p.get::<watt>()),
+            voltage: status.voltage.map(|v| v.get::<volt>()),
+            temperature: status.temperature.map(|t| t.get::<degree_celsius>()),
+            cycle_count: status.cycle_count.map(|c| c as f32),
+            health: status.state_of_health,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    batteries: Vec<Gauges>,
+    /// Alerts handed to the sinks, by kind.
+    notifications: BTreeMap<String, u64>,
+    /// Failed battery readings and alert deliveries, by backend: `source`
+    /// or the sink name.
+    backend_errors: BTreeMap<String, u64>,
+}
+
+impl Registry {
+    /// The Prometheus text exposition format.
+    fn render(&self) -> String {
+        type Field = fn(&Gauges) -> Option<f32>;
+        let gauges: &[(&str, &str, Field)] = &[
+            ("battery_charge_ratio", "Charge, 0 to 1.", |g| {
+                Some(g.charge)
+            }),
+            ("battery_energy_wh", "Energy left.", |g| g.energy),
+            ("battery_energy_full_wh", "Energy when full.", |g| {
+                g.energy_full
+            }),
+            (
+                "battery_energy_full_design_wh",
+                "Energy when full, as designed.",
+                |g| g.energy_full_design,
+            ),
+            ("battery_power_watts", "Power drawn or charged.", |g| {
+                g.power
+            }),
+            ("battery_voltage_volts", "Voltage.", |g| g.voltage),
+            ("battery_temperature_celsius", "Temperature.", |g| {
+                g.temperature
+            }),
+            ("battery_cycle_count", "Charge cycles so far.", |g| {
+                g.cycle_count
+            }),
+            (
+                "battery_health_ratio",
+                "Full energy relative to the design energy.",
+                |g| g.health,
+            ),
+        ];
+        let mut out = String::new();
+        for (name, help, field) in gauges {
+            let values: Vec<_> = self
+                .batteries
+                .iter()
+                .filter_map(|g| field(g).map(|v| (&g.id, v)))
+                .collect();
+            if values.is_empty() {
+                continue;
+            }
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            for (id, value) in values {
+                let _ = writeln!(out, "{}{{battery=\"{}\"}} {}", name, escape(id), value);
+            }
+        }
+        let counters = [
+            (
+                "battery_notifier_notifications_total",
+                "Alerts sent.",
+                "kind",
+                &self.notifications,
+            ),
+            (
+                "battery_notifier_backend_errors_total",
+                "Failed battery readings and alert deliveries.",
+                "backend",
+                &self.backend_errors,
+            ),
+        ];
+        for (name, help, label, values) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for (key, value) in values {
+                let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, escape(key), value);
+            }
+        }
+        out
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Counters shared between the exporter and the alert sinks.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl Metrics {
+    pub fn notification(&self, kind: &AlertKind) {
+        let mut registry = self.registry.lock().unwrap();
+        *registry.notifications.entry(kind.to_string()).or_default() += 1;
+    }
+
+    pub fn backend_error(&self, backend: &str) {
+        let mut registry = self.registry.lock().unwrap();
+        *registry
+            .backend_errors
+            .entry(backend.to_string())
+            .or_default() += 1;
+    }
+}
+
+/// Serves `/metrics` for Prometheus from a background thread, with the
+/// gauges of the last reading.
+pub struct MetricsExporter {
+    metrics: Metrics,
+}
+
+impl MetricsExporter {
+    pub fn start(addr: SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let metrics = Metrics::default();
+        let registry = metrics.registry.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Err(e) = stream.and_then(|stream| serve(stream, &registry)) {
+                    debug!("metrics request failed: {}", e);
+                }
+            }
+        });
+        Ok(MetricsExporter { metrics })
+    }
+
+    /// Handle for counting alerts and their delivery failures.
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+}
+
+fn serve(stream: TcpStream, registry: &Mutex<Registry>) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Skip the headers, nothing in them matters here.
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+    let mut words = request.split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some(path)) if path.split('?').next() == Some("/metrics") => {
+            ("200 OK", registry.lock().unwrap().render())
+        }
+        (Some("GET"), Some(_)) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+impl Publisher for MetricsExporter {
+    fn publish(&mut self, batteries: &[&BatteryStatus], _: Option<&BatteryStatus>, _: Instant) {
+        let gauges = batteries.iter().map(|b| Gauges::from(*b)).collect();
+        self.metrics.registry.lock().unwrap().batteries = gauges;
+    }
+
+    fn source_error(&mut self, _: &BatteryError) {
+        self.metrics.backend_error("source");
+    }
+}
@@ -0,0 +1,581 @@
+use std::mem;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use battery::units::thermodynamic_temperature::degree_celsius;
+use log::{debug, error, info};
+
+use crate::action::{self, Countdown};
+use crate::config::Config;
+use crate::drain::DrainDetector;
+use crate::estimator::Estimator;
+use crate::health::HealthState;
+use crate::notification;
+use crate::notifier::{self, Alert, AlertKind, Notifier};
+use crate::source::BatteryStatus;
+
+struct Tracked {
+    /// Last reading, with `state` replaced by the debounced state.
+    status: BatteryStatus,
+    /// A raw state differing from the debounced one, and when it was first
+    /// seen.
+    pending: Option<(battery::State, Instant)>,
+    estimator: Estimator,
+}
+
+impl Tracked {
+    /// Returns true once `raw` has been reported for at least
+    /// `config.state_dwell` and should replace the debounced state.
+    fn settle(&mut self, raw: battery::State, now: Instant, config: &Config) -> bool {
+        if raw == self.status.state {
+            self.pending = None;
+            return false;
+        }
+        let since = match self.pending {
+            Some((state, since)) if state == raw => since,
+            _ => now,
+        };
+        if now.duration_since(since) >= config.state_dwell {
+            self.pending = None;
+            true
+        } else {
+            self.pending = Some((raw, since));
+            false
+        }
+    }
+}
+
+/// Whether alerts are held back on request. The power action countdown is
+/// always shown, since it is the only way to cancel the action.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Snooze {
+    Off,
+    Until(Instant),
+    /// Until a battery settles in a new state.
+    UntilStateChange,
+}
+
+enum ActionState {
+    Armed,
+    Counting(Countdown),
+    /// Executed or cancelled, waiting for charging to resume.
+    Done,
+}
+
+/// Tracks the last seen batteries and decides which notifications each new
+/// reading should trigger.
+pub struct Monitor<'a> {
+    config: &'a Config,
+    notifier: Box<dyn Notifier>,
+    batteries: Vec<Tracked>,
+    /// Debounced aggregate of the last reading.
+    system: Option<BatteryStatus>,
+    system_estimator: Estimator,
+    /// Whether each of `config.thresholds` has been notified since it was
+    /// last re-armed.
+    thresholds_notified: Vec<bool>,
+    charged_notified: bool,
+    /// Whether each of `config.temperature_thresholds` has been reached since
+    /// it was last re-armed.
+    temperature_notified: Vec<bool>,
+    action: ActionState,
+    snooze: Snooze,
+    health: HealthState,
+    drain: DrainDetector,
+}
+
+impl<'a> Monitor<'a> {
+    /// Creates a monitor keeping the health marks and the typical power
+    /// draw in `health_path` and `drain_path`, or only in memory without
+    /// them.
+    pub fn new(
+        config: &'a Config,
+        notifier: Box<dyn Notifier>,
+        health_path: Option<PathBuf>,
+        drain_path: Option<PathBuf>,
+    ) -> Self {
+        Monitor {
+            config,
+            notifier,
+            batteries: Vec::new(),
+            system: None,
+            system_estimator: Estimator::new(config.estimator.window),
+            thresholds_notified: vec![false; config.thresholds.len()],
+            charged_notified: false,
+            temperature_notified: vec![false; config.temperature_thresholds.len()],
+            action: ActionState::Armed,
+            snooze: Snooze::Off,
+            health: HealthState::load(health_path),
+            drain: DrainDetector::load(drain_path),
+        }
+    }
+
+    pub fn system(&self) -> Option<&BatteryStatus> {
+        self.system.as_ref()
+    }
+
+    /// The debounced status of every battery from the last reading.
+    pub fn batteries(&self) -> impl Iterator<Item = &BatteryStatus> {
+        self.batteries.iter().map(|t| &t.status)
+    }
+
+    /// The next time after `now` a reading is needed even without any
+    /// change, to settle a pending state or finish a countdown.
+    pub fn next_deadline(&self, now: Instant) -> Option<Instant> {
+        let pending = self
+            .batteries
+            .iter()
+            .filter_map(|t| t.pending)
+            .map(|(_, since)| since + self.config.state_dwell);
+        let countdown = match &self.action {
+            ActionState::Counting(countdown) => Some(countdown.deadline),
+            _ => None,
+        };
+        pending.chain(countdown).filter(|d| *d > now).min()
+    }
+
+    pub fn snooze(&mut self, snooze: Snooze) {
+        self.snooze = snooze;
+    }
+
+    /// The snooze in effect at `now`.
+    pub fn snoozed(&self, now: Instant) -> Snooze {
+        match self.snooze {
+            Snooze::Until(until) if now >= until => Snooze::Off,
+            snooze => snooze,
+        }
+    }
+
+    /// Delivers `alert` unless snoozed, returning whether it went out.
+    fn send(&mut self, alert: Option<Alert>) -> bool {
+        let alert = match alert {
+            Some(alert) => alert,
+            None => return false,
+        };
+        let always = matches!(
+            alert.kind,
+            AlertKind::ActionCountdown | AlertKind::ActionAborted
+        );
+        if self.snooze != Snooze::Off && !always {
+            info!("snoozed {} alert: {}", alert.kind, alert.summary);
+            return false;
+        }
+        if let Err(e) = self.notifier.notify(&alert) {
+            error!("failed to deliver {} alert: {}", alert.kind, e);
+            return false;
+        }
+        true
+    }
+
+    /// Processes a new reading taken at `now`.
+    pub fn update(&mut self, new_batteries: Vec<BatteryStatus>, now: Instant) {
+        if self.snooze != Snooze::Off && self.snoozed(now) == Snooze::Off {
+            info!("snooze ended, resuming alerts");
+            self.snooze = Snooze::Off;
+        }
+        let multiple = new_batteries.len() > 1;
+        let mut tracked = Vec::with_capacity(new_batteries.len());
+        for mut status in new_batteries {
+            match self.batteries.iter().position(|t| t.status.id == status.id) {
+                Some(idx) => {
+                    let mut battery = self.batteries.remove(idx);
+                    if battery.settle(status.state, now, self.config) {
+                        if self.snooze == Snooze::UntilStateChange {
+                            info!("{} changed state, resuming alerts", status.id);
+                            self.snooze = Snooze::Off;
+                        }
+                        self.send(notification::state_changed(self.config, &status, multiple));
+                        debug!("new {} state {:?}", status.id, status.state);
+                    } else {
+                        status.state = battery.status.state;
+                    }
+                    battery
+                        .estimator
+                        .apply(&self.config.estimator, &mut status, now);
+                    battery.status = status;
+                    tracked.push(battery);
+                }
+                None => {
+                    info!("battery {} appeared", status.id);
+                    let mut estimator = Estimator::new(self.config.estimator.window);
+                    estimator.apply(&self.config.estimator, &mut status, now);
+                    tracked.push(Tracked {
+                        status,
+                        pending: None,
+                        estimator,
+                    });
+                }
+            }
+        }
+        for old in &self.batteries {
+            info!("battery {} disappeared", old.status.id);
+        }
+        self.batteries = tracked;
+        if self.config.notifications.health.enabled {
+            let batteries: Vec<_> = self.batteries().cloned().collect();
+            for status in &batteries {
+                self.check_health(status, multiple);
+            }
+        }
+
+        let debounced: Vec<_> = self.batteries.iter().map(|t| t.status.clone()).collect();
+        self.system = BatteryStatus::aggregate(&debounced);
+        if let Some(system) = &mut self.system {
+            self.system_estimator
+                .apply(&self.config.estimator, system, now);
+        }
+        if let Some(system) = self.system.clone() {
+            self.check_thresholds(&system);
+            self.check_charged(&system);
+            self.check_temperature(&system);
+            if self.config.notifications.drain.enabled {
+                self.check_drain(&system, now);
+            }
+            if self.config.action.enabled {
+                self.check_action(&system, now);
+            }
+        }
+    }
+
+    /// Fires the most severe threshold crossed while discharging. Levels
+    /// re-arm once charging resumes or the charge rises above them by more
+    /// than their hysteresis.
+    fn check_thresholds(&mut self, system: &BatteryStatus) {
+        let discharging = is_discharging(system.state);
+        let thresholds = &self.config.thresholds;
+        for (notified, threshold) in self.thresholds_notified.iter_mut().zip(thresholds) {
+            let rearm_level = threshold.rearm_level(self.config.threshold_hysteresis);
+            if !discharging || system.charge > rearm_level {
+                *notified = false;
+            }
+        }
+        if !discharging {
+            return;
+        }
+        // Thresholds are sorted by descending charge, so the last crossed one
+        // is the most severe. Skipped levels are not notified separately, and
+        // disabled ones do not count.
+        let crossed = thresholds
+            .iter()
+            .rposition(|t| t.enabled && system.charge <= t.level());
+        if let Some(idx) = crossed {
+            if !self.thresholds_notified[idx] {
+                let threshold = &thresholds[idx];
+                debug!(
+                    "charge crossed {} threshold at {}% - {}",
+                    threshold.name, threshold.charge, system.charge
+                );
+                self.send(notification::threshold(threshold, system));
+            }
+            self.thresholds_notified[..=idx].fill(true);
+        }
+    }
+
+    /// Warns once when the health drops below its level and once for every
+    /// cycle count passed. What was notified is kept across restarts, and
+    /// what was snoozed is notified once alerts resume.
+    fn check_health(&mut self, status: &BatteryStatus, multiple: bool) {
+        let config = &self.config.notifications.health;
+        let mut marks = self.health.marks(&status.id);
+        let health = status.state_of_health.filter(|h| h.is_finite() && *h > 0.0);
+        if let Some(health) = health {
+            let rearm_level = config.level() + self.config.threshold_hysteresis / 100.0;
+            if health < config.level() && !marks.below {
+                info!("{} health at {:.0}%", status.id, health * 100.0);
+                let reason = format!("Health dropped below {}%", config.health);
+                marks.below =
+                    self.send(notification::health(self.config, status, reason, multiple));
+            } else if health > rearm_level {
+                marks.below = false;
+            }
+        }
+        if let Some(count) = status.cycle_count {
+            let passed = config
+                .cycles
+                .iter()
+                .copied()
+                .filter(|c| count >= *c)
+                .max()
+                .unwrap_or(0);
+            let notified = if passed > marks.cycles {
+                info!("{} passed {} charge cycles", status.id, passed);
+                let reason = format!("Passed {} charge cycles", passed);
+                self.send(notification::health(self.config, status, reason, multiple))
+            } else {
+                true
+            };
+            // Also lowers the mark when the battery was replaced.
+            if notified {
+                marks.cycles = passed;
+            }
+        }
+        self.health.set(&status.id, marks);
+    }
+
+    /// Runs the command of every temperature threshold the hottest battery
+    /// reaches, and fires the most severe one. Levels re-arm, running their
+    /// recovery command, once the temperature drops below them by more than
+    /// the temperature hysteresis.
+    fn check_temperature(&mut self, system: &BatteryStatus) {
+        let celsius = match system.temperature.map(|t| t.get::<degree_celsius>()) {
+            Some(celsius) if celsius.is_finite() => celsius,
+            _ => return,
+        };
+        let thresholds = &self.config.temperature_thresholds;
+        let hysteresis = self.config.temperature_hysteresis;
+        for (notified, threshold) in self.temperature_notified.iter_mut().zip(thresholds) {
+            if *notified && celsius < threshold.celsius - hysteresis {
+                info!(
+                    "temperature back below {} at {:.1}°C",
+                    threshold.name, celsius
+                );
+                *notified = false;
+                run_temperature_command(&threshold.recover_command);
+            }
+        }
+        // Thresholds are sorted by ascending temperature, so the last reached
+        // one is the most severe. Skipped levels are not notified separately,
+        // and disabled ones only run their commands.
+        let notify = thresholds
+            .iter()
+            .rposition(|t| t.enabled && celsius >= t.celsius);
+        if let Some(idx) = notify.filter(|idx| !self.temperature_notified[*idx]) {
+            let threshold = &thresholds[idx];
+            info!("temperature reached {} at {:.1}°C", threshold.name, celsius);
+            self.send(notification::temperature(threshold, system, celsius));
+        }
+        if let Some(idx) = thresholds.iter().rposition(|t| celsius >= t.celsius) {
+            for (notified, threshold) in
+                self.temperature_notified[..=idx].iter_mut().zip(thresholds)
+            {
+                if !*notified {
+                    *notified = true;
+                    run_temperature_command(&threshold.command);
+                }
+            }
+        }
+    }
+
+    fn check_drain(&mut self, system: &BatteryStatus, now: Instant) {
+        let config = &self.config.notifications.drain;
+        if let Some(drain) = self.drain.update(config, system, now) {
+            info!(
+                "drawing {:.1} W, usually {:.1} W",
+                drain.power, drain.typical
+            );
+            self.send(notification::drain(self.config, system, &drain));
+        }
+    }
+
+    /// Reminds once to unplug when charging reaches the configured level or
+    /// the battery is full.
+    fn check_charged(&mut self, system: &BatteryStatus) {
+        let config = &self.config.notifications.charged;
+        let charged = system.state == battery::State::Full
+            || (system.state == battery::State::Charging && system.charge >= config.level());
+        if charged {
+            if !self.charged_notified {
+                self.send(notification::charged(self.config, system));
+                self.charged_notified = true;
+                debug!("charged to {} - {:?}", system.charge, system.state);
+            }
+        } else if is_discharging(system.state)
+            || system.charge < config.level() - self.config.threshold_hysteresis / 100.0
+        {
+            self.charged_notified = false;
+        }
+    }
+
+    /// Counts down to the power action while the charge stays at or below
+    /// its level, and runs it unless cancelled or charging resumes first.
+    fn check_action(&mut self, system: &BatteryStatus, now: Instant) {
+        let config = &self.config.action;
+        let rearm_level = config.level() + self.config.threshold_hysteresis / 100.0;
+        if !is_discharging(system.state) || system.charge > rearm_level {
+            if let ActionState::Counting(_) = mem::replace(&mut self.action, ActionState::Armed) {
+                let reason = match is_discharging(system.state) {
+                    true => format!("Charge recovered to {:.0}%", system.charge * 100.0),
+                    false => "Charging resumed".to_string(),
+                };
+                info!("{} aborted: {}", config.action, reason);
+                let alert = notification::action_aborted(self.config, system, &reason);
+                self.send(Some(alert));
+            }
+            return;
+        }
+        // Within the hysteresis a running countdown goes on, but no new one
+        // starts.
+        if system.charge > config.level() && !matches!(self.action, ActionState::Counting(_)) {
+            return;
+        }
+        self.action = match mem::replace(&mut self.action, ActionState::Done) {
+            ActionState::Armed => {
+                info!(
+                    "charge at {}, running {} in {:?}",
+                    system.charge, config.action, config.countdown
+                );
+                let countdown = Countdown::start(config, now);
+                let alert =
+                    notification::action_countdown(self.config, system, countdown.cancel_flag());
+                self.send(Some(alert));
+                ActionState::Counting(countdown)
+            }
+            ActionState::Counting(countdown) if countdown.is_cancelled() => {
+                self.notifier.countdown_ended();
+                ActionState::Done
+            }
+            ActionState::Counting(countdown) if now >= countdown.deadline => {
+                // Withdrawn first, so that it is gone after a resume.
+                self.notifier.countdown_ended();
+                info!("running {}", config.action);
+                if let Err(e) = action::execute(config) {
+                    error!("failed to run {}: {}", config.action, e);
+                }
+                ActionState::Done
+            }
+            state => state,
+        };
+    }
+}
+
+/// Anything but charging or full counts as discharging, so firmware
+/// reporting `Unknown` on battery power does not hide low charge.
+pub fn is_discharging(state: battery::State) -> bool {
+    !matches!(state, battery::State::Charging | battery::State::Full)
+}
+
+/// Starts a temperature threshold command, if one is configured, logging any
+/// failure.
+fn run_temperature_command(command: &[String]) {
+    if command.is_empty() {
+        return;
+    }
+    info!("running {:?}", command);
+    if let Err(e) = notifier::spawn_command(command) {
+        error!("failed to run {:?}: {}", command, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::error::Error;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use battery::State;
+
+    use super::*;
+    use crate::source::{BatterySource, ScriptedSource};
+
+    /// Keeps the kind of every alert it is given.
+    struct Recorder(Rc<RefCell<Vec<AlertKind>>>);
+
+    impl Notifier for Recorder {
+        fn notify(&mut self, alert: &Alert) -> Result<(), Box<dyn Error>> {
+            self.0.borrow_mut().push(alert.kind.clone());
+            Ok(())
+        }
+    }
+
+    fn status(state: State, charge: f32) -> Vec<BatteryStatus> {
+        vec![BatteryStatus::new("BAT0".to_string(), state, charge)]
+    }
+
+    /// Feeds one reading per second from `steps` into a monitor and returns
+    /// the alerts raised after each of them.
+    fn run(config: &Config, steps: Vec<Vec<BatteryStatus>>) -> Vec<Vec<AlertKind>> {
+        let alerts = Rc::new(RefCell::new(Vec::new()));
+        let mut monitor = Monitor::new(config, Box::new(Recorder(alerts.clone())), None, None);
+        let count = steps.len();
+        let mut source = ScriptedSource::new(steps);
+        let start = Instant::now();
+        (0..count)
+            .map(|secs| {
+                let batteries = source.batteries().unwrap();
+                monitor.update(batteries, start + Duration::from_secs(secs as u64));
+                alerts.borrow_mut().drain(..).collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn state_change_waits_for_dwell() {
+        let config = Config::default();
+        let steps = vec![
+            status(State::Discharging, 0.5),
+            status(State::Charging, 0.5),
+            status(State::Charging, 0.5),
+            status(State::Charging, 0.5),
+            status(State::Charging, 0.5),
+            status(State::Charging, 0.5),
+        ];
+        let alerts = run(&config, steps);
+        // Charging is first seen at 1s and lasts the 3s dwell time at 4s.
+        let changed = alerts
+            .iter()
+            .position(|a| a.contains(&AlertKind::StateChanged));
+        assert_eq!(changed, Some(4));
+        assert_eq!(alerts.concat(), vec![AlertKind::StateChanged]);
+    }
+
+    #[test]
+    fn flapping_unknown_is_ignored() {
+        let config = Config::default();
+        let steps = (0..10)
+            .map(|i| match i % 2 {
+                0 => status(State::Charging, 0.5),
+                _ => status(State::Unknown, 0.5),
+            })
+            .collect();
+        let alerts = run(&config, steps);
+        assert!(alerts.concat().is_empty(), "{:?}", alerts);
+    }
+
+    #[test]
+    fn threshold_rearms_above_hysteresis() {
+        let config = Config::default();
+        let warning = || AlertKind::Threshold("warning".to_string());
+        let charges = [0.35, 0.29, 0.31, 0.29, 0.33, 0.29];
+        let steps = charges
+            .iter()
+            .map(|c| status(State::Discharging, *c))
+            .collect();
+        let alerts = run(&config, steps);
+        assert_eq!(
+            alerts,
+            vec![
+                vec![],
+                vec![warning()],
+                // Within the 2% hysteresis of the 30% threshold.
+                vec![],
+                vec![],
+                vec![],
+                vec![warning()],
+            ]
+        );
+    }
+
+    #[test]
+    fn threshold_rearms_when_charging() {
+        let config = Config {
+            state_dwell: Duration::ZERO,
+            ..Config::default()
+        };
+        let low = || AlertKind::Threshold("low".to_string());
+        let steps = vec![
+            status(State::Discharging, 0.14),
+            status(State::Charging, 0.14),
+            status(State::Discharging, 0.14),
+        ];
+        let alerts = run(&config, steps);
+        assert_eq!(
+            alerts,
+            vec![
+                vec![low()],
+                vec![AlertKind::StateChanged],
+                vec![AlertKind::StateChanged, low()],
+            ]
+        );
+    }
+}
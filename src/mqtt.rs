@@ -0,0 +1,329 @@
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+use rumqttc::{Client, Event, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use serde_json::json;
+
+use crate::config::{self, MqttConfig};
+use crate::notifier::{Alert, Notifier};
+use crate::payload::{AlertPayload, BatteryPayload};
+use crate::publisher::Publisher;
+use crate::source::BatteryStatus;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// How long to wait on exit for queued messages to go out.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A Home Assistant entity announced for each battery, reading its value
+/// from the battery's state topic.
+struct Entity {
+    component: &'static str,
+    key: &'static str,
+    name: &'static str,
+    device_class: Option<&'static str>,
+    unit: Option<&'static str>,
+    template: &'static str,
+}
+
+const ENTITIES: &[Entity] = &[
+    Entity {
+        component: "sensor",
+        key: "charge",
+        name: "charge",
+        device_class: Some("battery"),
+        unit: Some("%"),
+        template: "{{ (value_json.charge * 100) | round(0) }}",
+    },
+    Entity {
+        component: "sensor",
+        key: "state",
+        name: "state",
+        device_class: None,
+        unit: None,
+        template: "{{ value_json.state }}",
+    },
+    Entity {
+        component: "sensor",
+        key: "power",
+        name: "power",
+        device_class: Some("power"),
+        unit: Some("W"),
+        template: "{{ value_json.power_w }}",
+    },
+    Entity {
+        component: "sensor",
+        key: "energy",
+        name: "energy",
+        device_class: Some("energy_storage"),
+        unit: Some("Wh"),
+        template: "{{ value_json.energy_wh }}",
+    },
+    Entity {
+        component: "sensor",
+        key: "time_to_empty",
+        name: "time to empty",
+        device_class: Some("duration"),
+        unit: Some("s"),
+        template: "{{ value_json.time_to_empty_secs }}",
+    },
+    Entity {
+        component: "sensor",
+        key: "time_to_full",
+        name: "time to full",
+        device_class: Some("duration"),
+        unit: Some("s"),
+        template: "{{ value_json.time_to_full_secs }}",
+    },
+    Entity {
+        component: "binary_sensor",
+        key: "charging",
+        name: "charging",
+        device_class: Some("battery_charging"),
+        unit: None,
+        template: "{{ 'ON' if value_json.state == 'charging' else 'OFF' }}",
+    },
+];
+
+/// Publishes every battery and the system aggregate to retained
+/// `<topic>/<id>/state` topics, refreshed every `interval` and whenever a
+/// state changes, and announces them to Home Assistant. Alerts go to
+/// `<topic>/events` through [`MqttPublisher::events`].
+pub struct MqttPublisher {
+    client: Client,
+    topic: String,
+    host: String,
+    node_id: String,
+    interval: Duration,
+    discovery: Option<String>,
+    /// Set by the connection thread on every reconnect, so that discovery and
+    /// state are sent again.
+    reconnected: Arc<AtomicBool>,
+    announced: Vec<String>,
+    /// When the state was last published, and the states it had.
+    last: Option<(Instant, Vec<(String, battery::State)>)>,
+    done: Receiver<()>,
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: `buf` is valid for writes of its whole length.
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if res != 0 {
+        return "localhost".to_string();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+impl MqttPublisher {
+    /// Starts connecting in the background. Messages published before the
+    /// broker is reachable are queued.
+    pub fn connect(config: &MqttConfig) -> Self {
+        let host = hostname();
+        let vars = [("host", host.clone())];
+        let client_id = config::render(&config.client_id, &vars);
+        let topic = config::render(&config.topic, &vars);
+        let availability = format!("{}/status", topic);
+
+        let mut options = MqttOptions::new(&client_id, &config.host, config.port);
+        options.set_last_will(LastWill::new(
+            &availability,
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.as_deref().unwrap_or_default());
+        }
+        let (client, mut connection) = Client::new(options, 64);
+
+        let reconnected = Arc::new(AtomicBool::new(false));
+        let (done_tx, done) = mpsc::channel();
+        let broker = format!("{}:{}", config.host, config.port);
+        let thread_client = client.clone();
+        let thread_reconnected = reconnected.clone();
+        thread::spawn(move || {
+            let mut warned = false;
+            let mut connected = false;
+            for event in connection.iter() {
+                match event {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("connected to MQTT broker {}", broker);
+                        warned = false;
+                        let _ = thread_client.try_publish(
+                            &availability,
+                            QoS::AtLeastOnce,
+                            true,
+                            "online",
+                        );
+                        // Messages queued before the first connection go out
+                        // now, only later ones need to be repeated.
+                        if connected {
+                            thread_reconnected.store(true, Ordering::Relaxed);
+                        }
+                        connected = true;
+                    }
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                    Ok(_) => {}
+                    Err(e) => {
+                        if warned {
+                            debug!("MQTT broker {} still unreachable: {}", broker, e);
+                        } else {
+                            warn!("MQTT broker {} unreachable, retrying: {}", broker, e);
+                            warned = true;
+                        }
+                        thread::sleep(RECONNECT_DELAY);
+                    }
+                }
+            }
+            let _ = done_tx.send(());
+        });
+
+        MqttPublisher {
+            client,
+            topic,
+            host,
+            node_id: client_id.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_"),
+            interval: config.interval,
+            discovery: config.discovery.then(|| config.discovery_prefix.clone()),
+            reconnected,
+            announced: Vec::new(),
+            last: None,
+            done,
+        }
+    }
+
+    /// Sink publishing alerts to `<topic>/events`.
+    pub fn events(&self) -> MqttNotifier {
+        MqttNotifier {
+            client: self.client.clone(),
+            topic: format!("{}/events", self.topic),
+        }
+    }
+
+    fn send(&self, topic: String, retain: bool, payload: String) {
+        if let Err(e) = self
+            .client
+            .try_publish(&topic, QoS::AtLeastOnce, retain, payload)
+        {
+            debug!("dropped MQTT message to {}: {}", topic, e);
+        }
+    }
+
+    fn announce(&self, status: &BatteryStatus) {
+        let prefix = match &self.discovery {
+            Some(prefix) => prefix,
+            None => return,
+        };
+        let device = json!({
+            "identifiers": [self.node_id],
+            "name": self.host,
+            "model": "battery-notifier",
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        });
+        for entity in ENTITIES {
+            let object_id = format!("{}_{}", status.id.to_lowercase(), entity.key);
+            let mut config = json!({
+                "name": format!("{} {}", status.id, entity.name),
+                "unique_id": format!("{}_{}", self.node_id, object_id),
+                "state_topic": format!("{}/{}/state", self.topic, status.id),
+                "value_template": entity.template,
+                "availability_topic": format!("{}/status", self.topic),
+                "device": device,
+            });
+            if let Some(device_class) = entity.device_class {
+                config["device_class"] = json!(device_class);
+            }
+            if let Some(unit) = entity.unit {
+                config["unit_of_measurement"] = json!(unit);
+            }
+            self.send(
+                format!(
+                    "{}/{}/{}/{}/config",
+                    prefix, entity.component, self.node_id, object_id
+                ),
+                true,
+                config.to_string(),
+            );
+        }
+    }
+}
+
+impl Publisher for MqttPublisher {
+    /// Publishes the state topics if they are due, a state changed or the
+    /// connection was re-established.
+    fn publish(
+        &mut self,
+        batteries: &[&BatteryStatus],
+        system: Option<&BatteryStatus>,
+        now: Instant,
+    ) {
+        let statuses: Vec<_> = batteries.iter().copied().chain(system).collect();
+        let states: Vec<_> = statuses.iter().map(|s| (s.id.clone(), s.state)).collect();
+        let reconnected = self.reconnected.swap(false, Ordering::Relaxed);
+        if reconnected {
+            self.announced.clear();
+        }
+        let due = match &self.last {
+            Some((at, last)) => now >= *at + self.interval || *last != states,
+            None => true,
+        };
+        if !due && !reconnected {
+            return;
+        }
+        for status in statuses {
+            if !self.announced.contains(&status.id) {
+                self.announce(status);
+                self.announced.push(status.id.clone());
+            }
+            match serde_json::to_string(&BatteryPayload::from(status)) {
+                Ok(payload) => {
+                    self.send(format!("{}/{}/state", self.topic, status.id), true, payload)
+                }
+                Err(e) => warn!("failed to encode {} state: {}", status.id, e),
+            }
+        }
+        self.last = Some((now, states));
+    }
+
+    /// When the state topics are next due for a refresh.
+    fn next_deadline(&self) -> Option<Instant> {
+        self.last.as_ref().map(|(at, _)| *at + self.interval)
+    }
+}
+
+impl Drop for MqttPublisher {
+    /// Marks the notifier offline and disconnects cleanly once the queued
+    /// messages are out, giving up after a short while if the broker is
+    /// unreachable.
+    fn drop(&mut self) {
+        self.send(
+            format!("{}/status", self.topic),
+            true,
+            "offline".to_string(),
+        );
+        if self.client.try_disconnect().is_ok() {
+            let _ = self.done.recv_timeout(DISCONNECT_TIMEOUT);
+        }
+    }
+}
+
+/// Publishes alerts as JSON to the events topic of an [`MqttPublisher`].
+pub struct MqttNotifier {
+    client: Client,
+    topic: String,
+}
+
+impl Notifier for MqttNotifier {
+    fn notify(&mut self, alert: &Alert) -> Result<(), Box<dyn Error>> {
+        let payload = serde_json::to_string(&AlertPayload::from(alert))?;
+        self.client
+            .try_publish(&self.topic, QoS::AtLeastOnce, false, payload)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,287 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time;
+
+use battery::units::time::second;
+use battery::units::Time;
+
+use crate::config::{self, Config, TemperatureThreshold, Threshold, Urgency};
+use crate::drain::Drain;
+use crate::notifier::{Alert, AlertKind};
+use crate::source::BatteryStatus;
+
+/// Formats a duration rounded to the minute as hours and minutes, e.g.
+/// `2h 05m` or `45m`.
+pub fn format_duration(duration: time::Duration) -> String {
+    let minutes = (duration.as_secs() + 30) / 60;
+    match (minutes / 60, minutes % 60) {
+        (0, m) => format!("{}m", m),
+        (h, m) => format!("{}h {:02}m", h, m),
+    }
+}
+
+pub fn format_time(t: Time) -> String {
+    let secs = t.get::<second>();
+    if secs.is_finite() && secs >= 0.0 {
+        format_duration(time::Duration::from_secs_f32(secs))
+    } else {
+        "unknown".to_string()
+    }
+}
+
+/// Describes whichever estimate applies, e.g. `1h 30m remaining`.
+pub fn format_estimate(status: &BatteryStatus) -> String {
+    match (status.time_to_full, status.time_to_empty) {
+        (Some(t), _) if status.state == battery::State::Charging => {
+            format!("{} until full", format_time(t))
+        }
+        (_, Some(t)) if status.state != battery::State::Charging => {
+            format!("{} remaining", format_time(t))
+        }
+        _ => String::new(),
+    }
+}
+
+/// One line per battery for `status` output, e.g. "BAT0: Discharging, 40%,
+/// 1h 30m remaining".
+pub fn status_line(status: &BatteryStatus) -> String {
+    let mut line = format!(
+        "{}: {:?}, {:.0}%",
+        status.id,
+        status.state,
+        status.charge * 100_f32
+    );
+    let estimate = format_estimate(status);
+    if !estimate.is_empty() {
+        line.push_str(", ");
+        line.push_str(&estimate);
+    }
+    if let Some(confidence) = status.estimate_confidence {
+        line.push_str(&format!(
+            " (estimated, {:.0}% confidence)",
+            confidence * 100.0
+        ));
+    }
+    line
+}
+
+/// Values for the template placeholders. `name` only mentions the battery id
+/// when there is more than one battery to tell apart.
+pub fn vars(status: &BatteryStatus, multiple: bool) -> Vec<(&'static str, String)> {
+    let name = if multiple {
+        format!("Battery {}", status.id)
+    } else {
+        "Battery".to_string()
+    };
+    let time_to_full = status.time_to_full.map(format_time).unwrap_or_default();
+    let time_to_empty = status.time_to_empty.map(format_time).unwrap_or_default();
+    vec![
+        ("name", name),
+        ("id", status.id.clone()),
+        ("state", format!("{:?}", status.state)),
+        ("charge", format!("{:.0}", status.charge * 100_f32)),
+        ("time_to_full", time_to_full),
+        ("time_to_empty", time_to_empty),
+        ("estimate", format_estimate(status)),
+    ]
+}
+
+fn build(
+    kind: AlertKind,
+    summary: &str,
+    body: &str,
+    urgency: Urgency,
+    status: &BatteryStatus,
+    vars: &[(&str, String)],
+) -> Alert {
+    Alert {
+        kind,
+        summary: config::render(summary, vars),
+        body: config::render(body, vars),
+        urgency,
+        status: status.clone(),
+        cancel: None,
+    }
+}
+
+pub fn state_changed(config: &Config, status: &BatteryStatus, multiple: bool) -> Option<Alert> {
+    let n = &config.notifications.state_changed;
+    n.enabled.then(|| {
+        build(
+            AlertKind::StateChanged,
+            &n.summary,
+            &n.body,
+            n.urgency,
+            status,
+            &vars(status, multiple),
+        )
+    })
+}
+
+pub fn charged(config: &Config, system: &BatteryStatus) -> Option<Alert> {
+    let n = &config.notifications.charged;
+    n.enabled.then(|| {
+        build(
+            AlertKind::Charged,
+            &n.summary,
+            &n.body,
+            n.urgency,
+            system,
+            &vars(system, false),
+        )
+    })
+}
+
+/// A battery's health crossed the configured level, or its cycle count one
+/// of the configured counts, as described by `reason`.
+pub fn health(
+    config: &Config,
+    status: &BatteryStatus,
+    reason: String,
+    multiple: bool,
+) -> Option<Alert> {
+    let n = &config.notifications.health;
+    let mut vars = vars(status, multiple);
+    vars.push((
+        "health",
+        status
+            .state_of_health
+            .map_or("unknown".to_string(), |h| format!("{:.0}", h * 100.0)),
+    ));
+    vars.push((
+        "cycles",
+        status
+            .cycle_count
+            .map_or("unknown".to_string(), |c| c.to_string()),
+    ));
+    vars.push(("reason", reason));
+    n.enabled.then(|| {
+        build(
+            AlertKind::Health,
+            &n.summary,
+            &n.body,
+            n.urgency,
+            status,
+            &vars,
+        )
+    })
+}
+
+/// The system battery drains much faster than usual.
+pub fn drain(config: &Config, system: &BatteryStatus, drain: &Drain) -> Option<Alert> {
+    let n = &config.notifications.drain;
+    let mut vars = vars(system, false);
+    vars.push(("power", format!("{:.1}", drain.power)));
+    vars.push(("typical", format!("{:.1}", drain.typical)));
+    vars.push(("factor", format!("{:.1}", drain.power / drain.typical)));
+    n.enabled.then(|| {
+        build(
+            AlertKind::Drain,
+            &n.summary,
+            &n.body,
+            n.urgency,
+            system,
+            &vars,
+        )
+    })
+}
+
+pub fn threshold(threshold: &Threshold, system: &BatteryStatus) -> Option<Alert> {
+    threshold.enabled.then(|| {
+        build(
+            AlertKind::Threshold(threshold.name.clone()),
+            &threshold.summary,
+            &threshold.body,
+            threshold.urgency,
+            system,
+            &vars(system, false),
+        )
+    })
+}
+
+/// The hottest battery reached `threshold`, at `celsius` degrees.
+pub fn temperature(
+    threshold: &TemperatureThreshold,
+    system: &BatteryStatus,
+    celsius: f32,
+) -> Option<Alert> {
+    let mut vars = vars(system, false);
+    vars.push(("temperature", format!("{:.0}", celsius)));
+    threshold.enabled.then(|| {
+        build(
+            AlertKind::Temperature(threshold.name.clone()),
+            &threshold.summary,
+            &threshold.body,
+            threshold.urgency,
+            system,
+            &vars,
+        )
+    })
+}
+
+/// The countdown announcing `config.action`. Sinks that can set `cancel`
+/// offer to cancel the action.
+pub fn action_countdown(config: &Config, system: &BatteryStatus, cancel: Arc<AtomicBool>) -> Alert {
+    let action = &config.action;
+    let mut vars = vars(system, false);
+    vars.push(("action", action.action.to_string()));
+    vars.push((
+        "countdown",
+        humantime::format_duration(action.countdown).to_string(),
+    ));
+    Alert {
+        cancel: Some(cancel),
+        ..build(
+            AlertKind::ActionCountdown,
+            &action.summary,
+            &action.body,
+            Urgency::Critical,
+            system,
+            &vars,
+        )
+    }
+}
+
+/// Withdraws the countdown after charging resumed or the charge recovered,
+/// as told by `reason`.
+pub fn action_aborted(config: &Config, system: &BatteryStatus, reason: &str) -> Alert {
+    Alert {
+        kind: AlertKind::ActionAborted,
+        summary: format!("Cancelled {}", config.action.action),
+        body: reason.to_string(),
+        urgency: Urgency::Normal,
+        status: system.clone(),
+        cancel: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn format(secs: u64) -> String {
+        format_duration(Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn formats_hours_and_minutes() {
+        assert_eq!(format(2 * 3600 + 5 * 60), "2h 05m");
+        assert_eq!(format(3600), "1h 00m");
+    }
+
+    #[test]
+    fn formats_minutes_alone() {
+        assert_eq!(format(45 * 60), "45m");
+        assert_eq!(format(0), "0m");
+    }
+
+    #[test]
+    fn rounds_to_the_nearest_minute() {
+        assert_eq!(format(29), "0m");
+        assert_eq!(format(30), "1m");
+        assert_eq!(format(3600 - 30), "1h 00m");
+        assert_eq!(format(3600 - 31), "59m");
+    }
+}
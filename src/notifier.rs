@@ -0,0 +1,275 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use log::{error, info};
+use notify_rust::{Notification, NotificationHandle, Timeout};
+
+use crate::config::{self, Config, SinkConfig, Urgency};
+use crate::metrics::Metrics;
+use crate::notification;
+use crate::source::BatteryStatus;
+use crate::webhook::WebhookNotifier;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AlertKind {
+    StateChanged,
+    /// Carries the threshold name.
+    Threshold(String),
+    Charged,
+    /// Carries the temperature threshold name.
+    Temperature(String),
+    Health,
+    Drain,
+    ActionCountdown,
+    ActionAborted,
+}
+
+impl Display for AlertKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AlertKind::StateChanged => write!(f, "state_changed"),
+            AlertKind::Threshold(_) => write!(f, "threshold"),
+            AlertKind::Charged => write!(f, "charged"),
+            AlertKind::Temperature(_) => write!(f, "temperature"),
+            AlertKind::Health => write!(f, "health"),
+            AlertKind::Drain => write!(f, "drain"),
+            AlertKind::ActionCountdown => write!(f, "action_countdown"),
+            AlertKind::ActionAborted => write!(f, "action_aborted"),
+        }
+    }
+}
+
+/// A rendered notification, independent of where it is delivered.
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub kind: AlertKind,
+    pub summary: String,
+    pub body: String,
+    pub urgency: Urgency,
+    /// The battery, or the system aggregate, the alert is about.
+    pub status: BatteryStatus,
+    /// Set on `ActionCountdown`. Sinks offering a way to cancel the action
+    /// store `true` here when the user does.
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+/// A destination for alerts.
+pub trait Notifier {
+    fn notify(&mut self, alert: &Alert) -> Result<(), Box<dyn Error>>;
+
+    /// Called once the power action countdown is over, whether the action
+    /// ran or was cancelled, so that sinks can withdraw it.
+    fn countdown_ended(&mut self) {}
+}
+
+/// Delivers every alert to several sinks. A failing sink is logged and does
+/// not keep the alert from the others.
+pub struct Fanout {
+    sinks: Vec<(String, Box<dyn Notifier>)>,
+    metrics: Option<Metrics>,
+}
+
+impl Fanout {
+    pub fn new() -> Self {
+        Fanout {
+            sinks: Vec::new(),
+            metrics: None,
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        let mut fanout = Fanout::new();
+        for sink in &config.sinks {
+            match sink {
+                SinkConfig::Desktop => {
+                    fanout.add("desktop", DesktopNotifier::new(config.notification_timeout))
+                }
+                SinkConfig::Stdout => fanout.add("stdout", StdoutNotifier),
+                SinkConfig::Command { command } => {
+                    fanout.add("command", CommandNotifier::new(command.clone()))
+                }
+                SinkConfig::Webhook {
+                    urls,
+                    timeout,
+                    retries,
+                } => fanout.add(
+                    "webhook",
+                    WebhookNotifier::new(urls.clone(), *timeout, *retries),
+                ),
+            }
+        }
+        fanout
+    }
+
+    pub fn add<N: Notifier + 'static>(&mut self, name: &str, notifier: N) {
+        self.sinks.push((name.to_string(), Box::new(notifier)));
+    }
+
+    /// Counts alerts and failed deliveries in `metrics`.
+    pub fn count(&mut self, metrics: Metrics) {
+        self.metrics = Some(metrics);
+    }
+}
+
+impl Notifier for Fanout {
+    fn notify(&mut self, alert: &Alert) -> Result<(), Box<dyn Error>> {
+        if let Some(metrics) = &self.metrics {
+            metrics.notification(&alert.kind);
+        }
+        for (name, sink) in &mut self.sinks {
+            if let Err(e) = sink.notify(alert) {
+                error!(
+                    "{} sink failed to deliver {} alert: {}",
+                    name, alert.kind, e
+                );
+                if let Some(metrics) = &self.metrics {
+                    metrics.backend_error(name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn countdown_ended(&mut self) {
+        for (_, sink) in &mut self.sinks {
+            sink.countdown_ended();
+        }
+    }
+}
+
+impl From<Urgency> for notify_rust::Urgency {
+    fn from(urgency: Urgency) -> Self {
+        match urgency {
+            Urgency::Low => notify_rust::Urgency::Low,
+            Urgency::Normal => notify_rust::Urgency::Normal,
+            Urgency::Critical => notify_rust::Urgency::Critical,
+        }
+    }
+}
+
+/// Desktop notifications through notify-rust. The power action countdown
+/// gets a Cancel button and is closed again once it is over.
+pub struct DesktopNotifier {
+    timeout: i32,
+    countdown: Option<NotificationHandle>,
+}
+
+impl DesktopNotifier {
+    pub fn new(timeout: i32) -> Self {
+        DesktopNotifier {
+            timeout,
+            countdown: None,
+        }
+    }
+}
+
+fn wait_for_cancel(handle: &NotificationHandle, cancel: Arc<AtomicBool>) {
+    let id = handle.id();
+    thread::spawn(move || {
+        let result = notify_rust::handle_action(id, |response| {
+            if let notify_rust::ActionResponse::Custom("cancel") = response {
+                info!("power action cancelled from notification");
+                cancel.store(true, Ordering::Relaxed);
+            }
+        });
+        if let Err(e) = result {
+            error!("failed to wait for notification action: {}", e);
+        }
+    });
+}
+
+impl Notifier for DesktopNotifier {
+    fn notify(&mut self, alert: &Alert) -> Result<(), Box<dyn Error>> {
+        if alert.kind == AlertKind::ActionAborted {
+            self.countdown_ended();
+            return Ok(());
+        }
+        let mut n = Notification::new()
+            .summary(&alert.summary)
+            .body(&alert.body)
+            .urgency(alert.urgency.into())
+            .timeout(self.timeout)
+            .finalize();
+        match &alert.cancel {
+            Some(cancel) => {
+                n.action("cancel", "Cancel").timeout(Timeout::Never);
+                let handle = n.show()?;
+                wait_for_cancel(&handle, cancel.clone());
+                self.countdown = Some(handle);
+            }
+            None => {
+                n.show()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn countdown_ended(&mut self) {
+        if let Some(handle) = self.countdown.take() {
+            handle.close();
+        }
+    }
+}
+
+/// Prints one line per alert, for logs and scripts.
+pub struct StdoutNotifier;
+
+impl Notifier for StdoutNotifier {
+    fn notify(&mut self, alert: &Alert) -> Result<(), Box<dyn Error>> {
+        let mut line = format!("[{}] {}: {}", alert.urgency, alert.kind, alert.summary);
+        if !alert.body.is_empty() {
+            line.push_str(" - ");
+            line.push_str(&alert.body.replace('\n', " "));
+        }
+        println!("{}", line.trim_end());
+        Ok(())
+    }
+}
+
+/// Runs a command per alert. Arguments may use the notification
+/// placeholders plus [`CommandNotifier::PLACEHOLDERS`].
+pub struct CommandNotifier {
+    command: Vec<String>,
+}
+
+impl CommandNotifier {
+    pub const PLACEHOLDERS: &'static [&'static str] = &["summary", "body", "urgency", "kind"];
+
+    pub fn new(command: Vec<String>) -> Self {
+        CommandNotifier { command }
+    }
+}
+
+impl Notifier for CommandNotifier {
+    fn notify(&mut self, alert: &Alert) -> Result<(), Box<dyn Error>> {
+        let mut vars = notification::vars(&alert.status, true);
+        vars.push(("summary", alert.summary.clone()));
+        vars.push(("body", alert.body.clone()));
+        vars.push(("urgency", alert.urgency.to_string()));
+        vars.push(("kind", alert.kind.to_string()));
+        let args: Vec<_> = self
+            .command
+            .iter()
+            .map(|arg| config::render(arg, &vars))
+            .collect();
+        spawn_command(&args)
+    }
+}
+
+/// Starts `command` without waiting for it, logging a failed exit.
+pub fn spawn_command(command: &[String]) -> Result<(), Box<dyn Error>> {
+    let (program, args) = command.split_first().ok_or("empty command")?;
+    let mut child = process::Command::new(program).args(args).spawn()?;
+    // Reap in the background so a slow command cannot stall monitoring.
+    let program = program.clone();
+    thread::spawn(move || match child.wait() {
+        Ok(status) if !status.success() => error!("{} exited with {}", program, status),
+        Ok(_) => {}
+        Err(e) => error!("failed to wait for {}: {}", program, e),
+    });
+    Ok(())
+}
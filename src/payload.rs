@@ -0,0 +1,76 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use battery::units::energy::watt_hour;
+use battery::units::power::watt;
+use battery::units::time::second;
+use serde::Serialize;
+
+use crate::notifier::{Alert, AlertKind};
+use crate::source::BatteryStatus;
+
+/// JSON view of a [`BatteryStatus`] for machine consumers.
+#[derive(Debug, Serialize)]
+pub struct BatteryPayload {
+    pub id: String,
+    pub state: String,
+    /// 0..1 ratio.
+    pub charge: f32,
+    pub energy_wh: Option<f32>,
+    pub energy_full_wh: Option<f32>,
+    pub power_w: Option<f32>,
+    pub time_to_full_secs: Option<f32>,
+    pub time_to_empty_secs: Option<f32>,
+}
+
+impl From<&BatteryStatus> for BatteryPayload {
+    fn from(status: &BatteryStatus) -> Self {
+        BatteryPayload {
+            id: status.id.clone(),
+            state: status.state.to_string(),
+            charge: status.charge,
+            energy_wh: status.energy.map(|e| e.get::<watt_hour>()),
+            energy_full_wh: status.energy_full.map(|e| e.get::<watt_hour>()),
+            power_w: status.energy_rate.map(|p| p.get::<watt>()),
+            time_to_full_secs: status.time_to_full.map(|t| t.get::<second>()),
+            time_to_empty_secs: status.time_to_empty.map(|t| t.get::<second>()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AlertPayload {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub kind: String,
+    /// Threshold name for `threshold` and `temperature` alerts.
+    pub threshold: Option<String>,
+    pub urgency: String,
+    pub summary: String,
+    pub body: String,
+    pub battery: BatteryPayload,
+}
+
+impl From<&Alert> for AlertPayload {
+    fn from(alert: &Alert) -> Self {
+        let threshold = match &alert.kind {
+            AlertKind::Threshold(name) | AlertKind::Temperature(name) => Some(name.clone()),
+            _ => None,
+        };
+        AlertPayload {
+            timestamp: unix_time(),
+            kind: alert.kind.to_string(),
+            threshold,
+            urgency: alert.urgency.to_string(),
+            summary: alert.summary.clone(),
+            body: alert.body.clone(),
+            battery: (&alert.status).into(),
+        }
+    }
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
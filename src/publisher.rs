@@ -0,0 +1,26 @@
+use std::time::Instant;
+
+use crate::source::{BatteryError, BatteryStatus};
+
+/// A consumer of every reading, unlike a [`Notifier`] which only sees
+/// alerts.
+///
+/// [`Notifier`]: crate::notifier::Notifier
+pub trait Publisher {
+    /// Called after each reading with the debounced batteries and their
+    /// aggregate.
+    fn publish(
+        &mut self,
+        batteries: &[&BatteryStatus],
+        system: Option<&BatteryStatus>,
+        now: Instant,
+    );
+
+    /// When the publisher next wants to be called even if nothing changes.
+    fn next_deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Called when reading the batteries failed.
+    fn source_error(&mut self, _error: &BatteryError) {}
+}
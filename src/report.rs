@@ -0,0 +1,398 @@
+use std::fmt::Write as _;
+use std::time::Duration;
+
+use crate::cli::ReportFormat;
+use crate::history::Sample;
+use crate::notification::format_duration;
+
+/// Samples further apart than this are not in the same session, e.g. after
+/// a suspend, unless downsampling spaced them further.
+const SESSION_GAP: Duration = Duration::from_secs(30 * 60);
+/// Sessions shorter than this are left out.
+const MIN_SESSION: i64 = 60;
+const LONGEST_SESSIONS: usize = 5;
+const RECENT_SESSIONS: usize = 50;
+
+/// A stretch of time a battery spent charging or discharging.
+struct Session {
+    battery: String,
+    charging: bool,
+    start: i64,
+    end: i64,
+    charge_start: f64,
+    charge_end: f64,
+    /// Watt-hours gained or used.
+    energy: Option<f64>,
+    /// Average watts charged or drawn.
+    power: Option<f64>,
+}
+
+impl Session {
+    fn duration(&self) -> i64 {
+        self.end - self.start
+    }
+
+    fn hours(&self) -> f64 {
+        self.duration() as f64 / 3600.0
+    }
+
+    /// Percentage points per hour, positive for either direction.
+    fn rate(&self) -> f64 {
+        (self.charge_end - self.charge_start).abs() * 100.0 / self.hours()
+    }
+}
+
+fn is_charging(state: &str) -> bool {
+    matches!(state, "charging" | "full")
+}
+
+/// Splits the samples of one battery, ordered by time, into sessions.
+fn sessions(samples: &[Sample], gap: i64) -> Vec<Session> {
+    let mut sessions = Vec::new();
+    let mut start = 0;
+    for i in 1..=samples.len() {
+        let split = match samples.get(i) {
+            Some(sample) => {
+                let prev = &samples[i - 1];
+                is_charging(&sample.state) != is_charging(&prev.state)
+                    || sample.time - prev.time > gap
+            }
+            None => true,
+        };
+        if split {
+            sessions.extend(session(&samples[start..i]));
+            start = i;
+        }
+    }
+    sessions
+}
+
+fn session(samples: &[Sample]) -> Option<Session> {
+    let (first, last) = (samples.first()?, samples.last()?);
+    if last.time - first.time < MIN_SESSION {
+        return None;
+    }
+    let charging = is_charging(&first.state);
+    let energy = match (first.energy, last.energy) {
+        (Some(start), Some(end)) => Some((end - start).abs()),
+        _ => None,
+    };
+    let hours = (last.time - first.time) as f64 / 3600.0;
+    let powers: Vec<_> = samples.iter().filter_map(|s| s.power).collect();
+    let power = match energy {
+        Some(energy) if energy > 0.0 => Some(energy / hours),
+        _ if !powers.is_empty() => Some(powers.iter().sum::<f64>() / powers.len() as f64),
+        _ => None,
+    };
+    Some(Session {
+        battery: first.battery.clone(),
+        charging,
+        start: first.time,
+        end: last.time,
+        charge_start: first.charge,
+        charge_end: last.charge,
+        energy,
+        power,
+    })
+}
+
+/// `YYYY-MM-DD HH:MM` in local time.
+fn local_time(time: i64) -> String {
+    let tm = local(time);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min
+    )
+}
+
+/// `YYYY-MM-DD` in local time.
+fn local_date(time: i64) -> String {
+    local_time(time)[..10].to_string()
+}
+
+fn local(time: i64) -> libc::tm {
+    let time = time as libc::time_t;
+    // SAFETY: an all-zero `tm` is valid, and both pointers are valid for the
+    // duration of the call.
+    unsafe {
+        let mut tm = std::mem::zeroed();
+        libc::localtime_r(&time, &mut tm);
+        tm
+    }
+}
+
+fn duration(secs: i64) -> String {
+    format_duration(Duration::from_secs(secs.max(0) as u64))
+}
+
+fn watt_hours(energy: Option<f64>) -> String {
+    energy.map_or("-".to_string(), |e| format!("{:.1} Wh", e))
+}
+
+fn watts(power: Option<f64>) -> String {
+    power.map_or("-".to_string(), |p| format!("{:.1} W", p))
+}
+
+fn percent(ratio: f64) -> String {
+    format!("{:.0}%", ratio * 100.0)
+}
+
+struct Table {
+    title: String,
+    note: Option<String>,
+    headers: Vec<&'static str>,
+    rows: Vec<Vec<String>>,
+}
+
+/// Per battery: time on battery, drain rates and the runtime they give on a
+/// full charge.
+fn summary(batteries: &[(&str, &[Sample], Vec<Session>)]) -> Table {
+    let mut rows = Vec::new();
+    for (battery, samples, sessions) in batteries {
+        let discharges: Vec<_> = sessions.iter().filter(|s| !s.charging).collect();
+        let hours: f64 = discharges.iter().map(|s| s.hours()).sum();
+        let drained: f64 = discharges.iter().map(|s| s.energy.unwrap_or(0.0)).sum();
+        let points: f64 = discharges
+            .iter()
+            .map(|s| (s.charge_start - s.charge_end).max(0.0) * 100.0)
+            .sum();
+        let power = (hours > 0.0 && drained > 0.0).then(|| drained / hours);
+        let rate = (hours > 0.0).then(|| points / hours);
+        // Runtime from full at the average drain rate.
+        let runtime = match rate {
+            Some(rate) if rate > 0.0 => duration((100.0 / rate * 3600.0) as i64),
+            _ => "-".to_string(),
+        };
+        let last = samples.last();
+        rows.push(vec![
+            battery.to_string(),
+            discharges.len().to_string(),
+            duration((hours * 3600.0) as i64),
+            watts(power),
+            rate.map_or("-".to_string(), |r| format!("{:.1}%/h", r)),
+            runtime,
+            watt_hours(last.and_then(|s| s.energy_full)),
+            watt_hours(last.and_then(|s| s.energy_full_design)),
+            last.and_then(|s| s.cycle_count)
+                .map_or("-".to_string(), |c| c.to_string()),
+        ]);
+    }
+    Table {
+        title: "Summary".to_string(),
+        note: None,
+        headers: vec![
+            "Battery",
+            "Discharges",
+            "On battery",
+            "Average drain",
+            "Drain rate",
+            "Full runtime",
+            "Full capacity",
+            "Design capacity",
+            "Cycles",
+        ],
+        rows,
+    }
+}
+
+/// Full capacity, its ratio to the design capacity and the cycle count at
+/// the end of each day.
+fn capacity(batteries: &[(&str, &[Sample], Vec<Session>)]) -> Table {
+    let mut rows = Vec::new();
+    for (battery, samples, _) in batteries {
+        let mut days: Vec<(String, &Sample)> = Vec::new();
+        for sample in samples.iter() {
+            let day = local_date(sample.time);
+            match days.last_mut() {
+                Some((last, latest)) if *last == day => *latest = sample,
+                _ => days.push((day, sample)),
+            }
+        }
+        for (day, sample) in days {
+            if sample.energy_full.is_none() && sample.cycle_count.is_none() {
+                continue;
+            }
+            let health = match (sample.energy_full, sample.energy_full_design) {
+                (Some(full), Some(design)) if design > 0.0 => percent(full / design),
+                _ => "-".to_string(),
+            };
+            rows.push(vec![
+                battery.to_string(),
+                day,
+                watt_hours(sample.energy_full),
+                watt_hours(sample.energy_full_design),
+                health,
+                sample
+                    .cycle_count
+                    .map_or("-".to_string(), |c| c.to_string()),
+            ]);
+        }
+    }
+    Table {
+        title: "Capacity history".to_string(),
+        note: Some("Full capacity and cycle count at the end of each day.".to_string()),
+        headers: vec![
+            "Battery",
+            "Date",
+            "Full capacity",
+            "Design capacity",
+            "Health",
+            "Cycles",
+        ],
+        rows,
+    }
+}
+
+fn session_table(title: &str, note: Option<&str>, sessions: &[&Session]) -> Table {
+    let rows = sessions
+        .iter()
+        .map(|s| {
+            vec![
+                s.battery.clone(),
+                if s.charging {
+                    "charging"
+                } else {
+                    "discharging"
+                }
+                .to_string(),
+                local_time(s.start),
+                duration(s.duration()),
+                format!("{} → {}", percent(s.charge_start), percent(s.charge_end)),
+                watt_hours(s.energy),
+                watts(s.power),
+                format!("{:.1}%/h", s.rate()),
+            ]
+        })
+        .collect();
+    Table {
+        title: title.to_string(),
+        note: note.map(str::to_string),
+        headers: vec![
+            "Battery", "State", "Start", "Duration", "Charge", "Energy", "Power", "Rate",
+        ],
+        rows,
+    }
+}
+
+/// Renders a report of `samples`, ordered by battery and time, as recorded
+/// by [`History`]. `downsample` is the history's bucket length, so that
+/// downsampled samples are not taken for gaps.
+///
+/// [`History`]: crate::history::History
+pub fn render(samples: &[Sample], downsample: Duration, format: ReportFormat) -> String {
+    let gap = SESSION_GAP.max(downsample * 2).as_secs() as i64;
+    let mut batteries = Vec::new();
+    let mut start = 0;
+    for i in 1..=samples.len() {
+        if samples
+            .get(i)
+            .is_none_or(|s| s.battery != samples[i - 1].battery)
+        {
+            let samples = &samples[start..i];
+            batteries.push((samples[0].battery.as_str(), samples, sessions(samples, gap)));
+            start = i;
+        }
+    }
+
+    let all: Vec<_> = batteries.iter().flat_map(|(_, _, s)| s).collect();
+    let mut longest: Vec<_> = all.iter().copied().filter(|s| !s.charging).collect();
+    longest.sort_by_key(|s| -s.duration());
+    longest.truncate(LONGEST_SESSIONS);
+    let mut recent = all.clone();
+    recent.sort_by_key(|s| -s.start);
+    recent.truncate(RECENT_SESSIONS);
+
+    let period = match (
+        samples.iter().map(|s| s.time).min(),
+        samples.iter().map(|s| s.time).max(),
+    ) {
+        (Some(first), Some(last)) => format!("{} to {}", local_time(first), local_time(last)),
+        _ => "no samples recorded".to_string(),
+    };
+    let tables = [
+        summary(&batteries),
+        session_table("Longest discharges", None, &longest),
+        capacity(&batteries),
+        session_table(
+            "Recent sessions",
+            Some("Newest first, with drain or charge rates in percent per hour."),
+            &recent,
+        ),
+    ];
+    match format {
+        ReportFormat::Markdown => markdown(&period, &tables),
+        ReportFormat::Html => html(&period, &tables),
+    }
+}
+
+fn markdown(period: &str, tables: &[Table]) -> String {
+    let mut out = format!("# Battery report\n\n{}\n", period);
+    for table in tables {
+        let _ = write!(out, "\n## {}\n\n", table.title);
+        if let Some(note) = &table.note {
+            let _ = writeln!(out, "{}\n", note);
+        }
+        if table.rows.is_empty() {
+            out.push_str("Nothing recorded.\n");
+            continue;
+        }
+        let _ = writeln!(out, "| {} |", table.headers.join(" | "));
+        let _ = writeln!(out, "|{}", "---|".repeat(table.headers.len()));
+        for row in &table.rows {
+            let _ = writeln!(out, "| {} |", row.join(" | ").replace('\n', " "));
+        }
+    }
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+const STYLE: &str = "body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; margin-bottom: 1em; }
+th, td { border: 1px solid #ccc; padding: 0.3em 0.7em; text-align: left; }
+th { background: #eee; }
+tr:nth-child(even) td { background: #f8f8f8; }";
+
+fn html(period: &str, tables: &[Table]) -> String {
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>Battery report</title>\n<style>\n{}\n</style>\n</head>\n<body>\n\
+         <h1>Battery report</h1>\n<p>{}</p>\n",
+        STYLE,
+        escape(period)
+    );
+    for table in tables {
+        let _ = writeln!(out, "<h2>{}</h2>", escape(&table.title));
+        if let Some(note) = &table.note {
+            let _ = writeln!(out, "<p>{}</p>", escape(note));
+        }
+        if table.rows.is_empty() {
+            out.push_str("<p>Nothing recorded.</p>\n");
+            continue;
+        }
+        out.push_str("<table>\n<tr>");
+        for header in &table.headers {
+            let _ = write!(out, "<th>{}</th>", escape(header));
+        }
+        out.push_str("</tr>\n");
+        for row in &table.rows {
+            out.push_str("<tr>");
+            for cell in row {
+                let _ = write!(out, "<td>{}</td>", escape(cell));
+            }
+            out.push_str("</tr>\n");
+        }
+        out.push_str("</table>\n");
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+use log::debug;
+
+use crate::config::Config;
+use crate::source::BatteryStatus;
+
+/// Picks how long to wait before the next poll.
+pub struct Scheduler<'a> {
+    config: &'a Config,
+    /// Interval used regardless of the charge, when polling is not adaptive.
+    fixed: Option<Duration>,
+    last: Option<Duration>,
+}
+
+impl<'a> Scheduler<'a> {
+    pub fn new(config: &'a Config, fixed: Option<Duration>) -> Self {
+        Scheduler {
+            config,
+            fixed,
+            last: None,
+        }
+    }
+
+    pub fn interval(&mut self, system: Option<&BatteryStatus>) -> Duration {
+        let interval = match (self.fixed, system) {
+            (Some(fixed), _) => fixed,
+            (None, Some(system)) => self.adaptive(system),
+            (None, None) => self.config.polling.min_interval,
+        };
+        if self.last != Some(interval) {
+            debug!("polling every {:?}", interval);
+            self.last = Some(interval);
+        }
+        interval
+    }
+
+    /// Scales linearly from the max interval, at `band` percent or more away
+    /// from the next level, down to the min interval on the level itself.
+    fn adaptive(&self, system: &BatteryStatus) -> Duration {
+        let polling = &self.config.polling;
+        let distance = match self.distance(system) {
+            Some(distance) => distance,
+            None => return polling.max_interval,
+        };
+        let ratio = (distance / polling.band).clamp(0.0, 1.0);
+        let span = (polling.max_interval - polling.min_interval).as_millis() as f32;
+        polling.min_interval + Duration::from_millis((span * ratio).round() as u64)
+    }
+
+    /// Percent between the charge and the nearest level it is heading for:
+    /// thresholds and the power action while discharging, the charged
+    /// reminder while charging.
+    fn distance(&self, system: &BatteryStatus) -> Option<f32> {
+        let charge = system.charge * 100.0;
+        let config = self.config;
+        match system.state {
+            battery::State::Charging => {
+                let charged = &config.notifications.charged;
+                (charged.enabled && charged.charge >= charge).then_some(charged.charge - charge)
+            }
+            battery::State::Full => None,
+            _ => {
+                let action = config.action.enabled.then_some(config.action.charge);
+                config
+                    .thresholds
+                    .iter()
+                    .filter(|t| t.enabled)
+                    .map(|t| t.charge)
+                    .chain(action)
+                    .filter(|level| *level <= charge)
+                    .map(|level| charge - level)
+                    .reduce(f32::min)
+            }
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt::{Debug, Display};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use battery::units::energy::joule;
+use battery::units::power::watt;
+use battery::units::time::second;
+use battery::units::{ElectricPotential, Energy, Power, ThermodynamicTemperature, Time};
+
+/// Id of the aggregate status combining every battery.
+pub const SYSTEM_ID: &str = "system";
+
+#[derive(Debug, Clone)]
+pub struct BatteryStatus {
+    pub id: String,
+    pub state: battery::State,
+    pub time_to_full: Option<Time>,
+    pub time_to_empty: Option<Time>,
+    pub charge: f32,
+    pub energy: Option<Energy>,
+    pub energy_full: Option<Energy>,
+    pub energy_full_design: Option<Energy>,
+    pub energy_rate: Option<Power>,
+    pub voltage: Option<ElectricPotential>,
+    pub temperature: Option<ThermodynamicTemperature>,
+    pub cycle_count: Option<u32>,
+    /// Full capacity relative to the design capacity, 0..1 ratio.
+    pub state_of_health: Option<f32>,
+    /// Set when the time to empty or to full is the notifier's own
+    /// estimate rather than the firmware's, to its confidence from 0 to 1.
+    pub estimate_confidence: Option<f32>,
+}
+
+impl BatteryStatus {
+    /// A battery known only by its state and charge, every other reading
+    /// unknown.
+    pub fn new(id: String, state: battery::State, charge: f32) -> Self {
+        BatteryStatus {
+            id,
+            state,
+            time_to_full: None,
+            time_to_empty: None,
+            charge,
+            energy: None,
+            energy_full: None,
+            energy_full_design: None,
+            energy_rate: None,
+            voltage: None,
+            temperature: None,
+            cycle_count: None,
+            state_of_health: None,
+            estimate_confidence: None,
+        }
+    }
+
+    /// Combines several batteries into one "system" status. Charge is
+    /// weighted by energy when every battery reports it, otherwise it is the
+    /// plain average of the charges.
+    pub fn aggregate(batteries: &[BatteryStatus]) -> Option<BatteryStatus> {
+        if batteries.len() == 1 {
+            return Some(BatteryStatus {
+                id: SYSTEM_ID.to_string(),
+                ..batteries[0].clone()
+            });
+        }
+        if batteries.is_empty() {
+            return None;
+        }
+        let sum = |f: fn(&BatteryStatus) -> Option<Energy>| {
+            batteries
+                .iter()
+                .map(f)
+                .try_fold(Energy::new::<joule>(0.0), |acc, e| Some(acc + e?))
+        };
+        let energy = sum(|b| b.energy);
+        let energy_full = sum(|b| b.energy_full);
+        let energy_full_design = sum(|b| b.energy_full_design);
+        let energy_rate = batteries
+            .iter()
+            .map(|b| b.energy_rate)
+            .try_fold(Power::new::<watt>(0.0), |acc, p| Some(acc + p?));
+        let charge = match (energy, energy_full) {
+            (Some(now), Some(full)) if full.value > 0.0 => (now / full).value,
+            _ => batteries.iter().map(|b| b.charge).sum::<f32>() / batteries.len() as f32,
+        };
+        let state = aggregate_state(&batteries.iter().map(|b| b.state).collect::<Vec<_>>());
+        let time_to_full = match (state, energy, energy_full, energy_rate) {
+            (battery::State::Charging, Some(now), Some(full), Some(rate))
+                if rate.value > 0.0 && full > now =>
+            {
+                Some((full - now) / rate)
+            }
+            (battery::State::Charging, ..) => batteries
+                .iter()
+                .filter_map(|b| b.time_to_full)
+                .reduce(|a, b| a + b),
+            _ => None,
+        };
+        let time_to_empty = match (state, energy, energy_rate) {
+            (battery::State::Charging, ..) => None,
+            (_, Some(now), Some(rate)) if rate.value > 0.0 => Some(now / rate),
+            _ => batteries
+                .iter()
+                .filter_map(|b| b.time_to_empty)
+                .reduce(|a, b| a + b),
+        };
+        let state_of_health = match (energy_full, energy_full_design) {
+            (Some(full), Some(design)) if design.value > 0.0 => Some((full / design).value),
+            _ => None,
+        };
+        Some(BatteryStatus {
+            id: SYSTEM_ID.to_string(),
+            state,
+            time_to_full,
+            time_to_empty,
+            charge: charge.clamp(0.0, 1.0),
+            energy,
+            energy_full,
+            energy_full_design,
+            energy_rate,
+            // Voltages of separate packs do not add up to anything useful.
+            voltage: None,
+            // The hottest battery, and the most worn one.
+            temperature: batteries
+                .iter()
+                .filter_map(|b| b.temperature)
+                .reduce(|a, b| if b > a { b } else { a }),
+            cycle_count: batteries.iter().filter_map(|b| b.cycle_count).max(),
+            state_of_health,
+            estimate_confidence: None,
+        })
+    }
+}
+
+/// Any charging battery makes the system charging, any discharging one makes
+/// it discharging, and it is only full or empty once every battery is.
+fn aggregate_state(states: &[battery::State]) -> battery::State {
+    let all = |s| states.iter().all(|state| *state == s);
+    if states.contains(&battery::State::Charging) {
+        battery::State::Charging
+    } else if states.contains(&battery::State::Discharging) {
+        battery::State::Discharging
+    } else if all(battery::State::Full) {
+        battery::State::Full
+    } else if all(battery::State::Empty) {
+        battery::State::Empty
+    } else {
+        battery::State::Unknown
+    }
+}
+
+#[derive(Debug)]
+pub enum BatteryError {
+    FailedToGetState,
+    LibError(battery::Error),
+    Io(io::Error),
+    InvalidScript(String),
+}
+
+impl Display for BatteryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BatteryError::FailedToGetState => write!(f, "failed to get battery state"),
+            BatteryError::LibError(e) => write!(f, "{:?}", e),
+            BatteryError::Io(e) => write!(f, "{}", e),
+            BatteryError::InvalidScript(line) => write!(f, "invalid script line: {}", line),
+        }
+    }
+}
+
+impl Error for BatteryError {}
+
+impl From<battery::Error> for BatteryError {
+    fn from(e: battery::Error) -> Self {
+        BatteryError::LibError(e)
+    }
+}
+
+impl From<io::Error> for BatteryError {
+    fn from(e: io::Error) -> Self {
+        BatteryError::Io(e)
+    }
+}
+
+/// Anything the main loop can poll for the current battery statuses.
+pub trait BatterySource {
+    /// Returns every battery the source knows about, in a stable order.
+    fn batteries(&mut self) -> Result<Vec<BatteryStatus>, BatteryError>;
+}
+
+/// Source backed by the `battery` crate. The manager is created once and
+/// reused for every poll.
+pub struct ManagerSource {
+    manager: battery::Manager,
+}
+
+impl ManagerSource {
+    pub fn new() -> Result<Self, BatteryError> {
+        Ok(ManagerSource {
+            manager: battery::Manager::new()?,
+        })
+    }
+}
+
+impl BatterySource for ManagerSource {
+    fn batteries(&mut self) -> Result<Vec<BatteryStatus>, BatteryError> {
+        let mut batteries = Vec::new();
+        for (idx, bat) in self.manager.batteries()?.enumerate() {
+            let bat = bat?;
+            batteries.push(BatteryStatus {
+                id: format!("BAT{}", idx),
+                state: bat.state(),
+                time_to_full: bat.time_to_full(),
+                time_to_empty: bat.time_to_empty(),
+                charge: bat.state_of_charge().value,
+                energy: Some(bat.energy()),
+                energy_full: Some(bat.energy_full()),
+                energy_full_design: Some(bat.energy_full_design()),
+                energy_rate: Some(bat.energy_rate()),
+                voltage: Some(bat.voltage()),
+                temperature: bat.temperature(),
+                cycle_count: bat.cycle_count(),
+                state_of_health: Some(bat.state_of_health().value),
+                estimate_confidence: None,
+            });
+        }
+        if batteries.is_empty() {
+            return Err(BatteryError::FailedToGetState);
+        }
+        Ok(batteries)
+    }
+}
+
+/// Source replaying a fixed list of battery snapshots, one per poll. Once the
+/// list is exhausted the last snapshot is repeated.
+pub struct ScriptedSource {
+    steps: VecDeque<Vec<BatteryStatus>>,
+}
+
+impl ScriptedSource {
+    pub fn new(steps: Vec<Vec<BatteryStatus>>) -> Self {
+        ScriptedSource {
+            steps: steps.into(),
+        }
+    }
+
+    /// Loads a script where every non-empty line is one poll of
+    /// `<state> <charge> [estimate_secs]`, e.g. `discharging 0.42 3600`. The
+    /// estimate is the time to full while charging and the time to empty
+    /// otherwise.
+    /// Several batteries are separated by `|` on the same line. Lines
+    /// starting with `#` are ignored.
+    pub fn from_file(path: &Path) -> Result<Self, BatteryError> {
+        let content = fs::read_to_string(path)?;
+        let mut steps = Vec::new();
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let batteries = line
+                .split('|')
+                .enumerate()
+                .map(|(idx, bat)| parse_script_battery(idx, bat.trim()))
+                .collect::<Result<_, _>>()?;
+            steps.push(batteries);
+        }
+        Ok(ScriptedSource::new(steps))
+    }
+}
+
+fn parse_script_battery(idx: usize, line: &str) -> Result<BatteryStatus, BatteryError> {
+    let invalid = || BatteryError::InvalidScript(line.to_string());
+    let mut parts = line.split_whitespace();
+    let state = parts
+        .next()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(invalid)?;
+    let charge = parts
+        .next()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(invalid)?;
+    let estimate = match parts.next() {
+        Some(s) => {
+            let secs: f32 = s.parse().map_err(|_| invalid())?;
+            Some(Time::new::<second>(secs))
+        }
+        None => None,
+    };
+    let (time_to_full, time_to_empty) = match state {
+        battery::State::Charging => (estimate, None),
+        _ => (None, estimate),
+    };
+    Ok(BatteryStatus {
+        time_to_full,
+        time_to_empty,
+        ..BatteryStatus::new(format!("BAT{}", idx), state, charge)
+    })
+}
+
+impl BatterySource for ScriptedSource {
+    fn batteries(&mut self) -> Result<Vec<BatteryStatus>, BatteryError> {
+        match self.steps.len() {
+            0 => Err(BatteryError::FailedToGetState),
+            1 => Ok(self.steps[0].clone()),
+            _ => Ok(self.steps.pop_front().unwrap()),
+        }
+    }
+}
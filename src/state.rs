@@ -0,0 +1,54 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// `$XDG_STATE_HOME/battery-notifier`, falling back to `~/.local/state` when
+/// `XDG_STATE_HOME` is unset.
+pub fn dir() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_STATE_HOME").filter(|v| !v.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".local/state"),
+    };
+    Some(base.join("battery-notifier"))
+}
+
+/// Reads the JSON state saved at `path`, or `None` when there is none yet.
+/// An unreadable file is logged as `what` and ignored.
+pub fn load<T: DeserializeOwned>(path: &Path, what: &str) -> Option<T> {
+    match read(path) {
+        Ok(value) => Some(value),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => {
+            warn!("ignoring {} {:?}: {}", what, path, e);
+            None
+        }
+    }
+}
+
+/// Saves `value` as JSON to `path`, logging a failure as `what`.
+pub fn save<T: Serialize>(path: &Path, value: &T, what: &str) {
+    if let Err(e) = write(path, value) {
+        warn!("failed to save {} {:?}: {}", what, path, e);
+    }
+}
+
+fn read<T: DeserializeOwned>(path: &Path) -> io::Result<T> {
+    let text = fs::read_to_string(path)?;
+    serde_json::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let text = serde_json::to_string_pretty(value)?;
+    // Renamed into place so that a crash never leaves half a file.
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, text)?;
+    fs::rename(&tmp, path)
+}
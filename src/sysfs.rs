@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use battery::units::electric_potential::microvolt;
+use battery::units::energy::microwatt_hour;
+use battery::units::power::microwatt;
+use battery::units::thermodynamic_temperature::degree_celsius;
+use battery::units::time::hour;
+use battery::units::{ElectricPotential, Energy, Power, ThermodynamicTemperature, Time};
+use log::{debug, trace};
+
+use crate::source::{BatteryError, BatterySource, BatteryStatus};
+
+pub const DEFAULT_SYSFS_ROOT: &str = "/sys/class/power_supply";
+
+/// Attributes read from standalone files when `uevent` does not provide them.
+const ATTRIBUTES: &[&str] = &[
+    "type",
+    "scope",
+    "status",
+    "capacity",
+    "energy_now",
+    "energy_full",
+    "energy_full_design",
+    "charge_now",
+    "charge_full",
+    "charge_full_design",
+    "power_now",
+    "current_now",
+    "voltage_now",
+    "temp",
+    "cycle_count",
+];
+
+/// One `power_supply` class device, with attribute names lowercased and the
+/// `POWER_SUPPLY_` prefix stripped, e.g. `energy_now`.
+#[derive(Debug)]
+struct PowerSupply {
+    name: String,
+    props: HashMap<String, String>,
+}
+
+impl PowerSupply {
+    fn read(dir: &Path) -> io::Result<Self> {
+        let name = dir
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut props = HashMap::new();
+        if let Ok(uevent) = fs::read_to_string(dir.join("uevent")) {
+            for line in uevent.lines() {
+                if let Some((key, value)) = line.split_once('=') {
+                    let key = key.trim_start_matches("POWER_SUPPLY_").to_ascii_lowercase();
+                    props.insert(key, value.trim().to_string());
+                }
+            }
+        }
+        for attr in ATTRIBUTES {
+            if props.contains_key(*attr) {
+                continue;
+            }
+            match fs::read_to_string(dir.join(attr)) {
+                Ok(value) => {
+                    props.insert(attr.to_string(), value.trim().to_string());
+                }
+                // Missing attributes are normal, and some drivers return
+                // ENODATA for attributes they cannot report right now.
+                Err(e) => trace!("{}: skipping {}: {}", name, attr, e),
+            }
+        }
+        Ok(PowerSupply { name, props })
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.props.get(key).map(String::as_str)
+    }
+
+    fn number(&self, key: &str) -> Option<f32> {
+        self.get(key).and_then(|v| v.parse().ok())
+    }
+
+    /// A system battery: peripherals such as mice report their own battery
+    /// with the `Device` scope and are left out.
+    fn is_battery(&self) -> bool {
+        self.get("type")
+            .is_some_and(|t| t.eq_ignore_ascii_case("Battery"))
+            && !self
+                .get("scope")
+                .is_some_and(|s| s.eq_ignore_ascii_case("Device"))
+    }
+
+    fn state(&self) -> battery::State {
+        match self.get("status") {
+            // The kernel reports "Not charging" when a charge threshold is
+            // reached on AC power.
+            Some(s) if s.eq_ignore_ascii_case("Not charging") => battery::State::Full,
+            Some(s) => s.parse().unwrap_or(battery::State::Unknown),
+            None => battery::State::Unknown,
+        }
+    }
+
+    /// Returns `(now, full, rate)` in matching units, preferring energy
+    /// (µWh, µW) over charge (µAh, µA).
+    fn levels(&self) -> Option<(f32, f32, Option<f32>)> {
+        if let (Some(now), Some(full)) = (self.number("energy_now"), self.number("energy_full")) {
+            return Some((now, full, self.number("power_now")));
+        }
+        if let (Some(now), Some(full)) = (self.number("charge_now"), self.number("charge_full")) {
+            return Some((now, full, self.number("current_now")));
+        }
+        None
+    }
+
+    fn charge(&self) -> Option<f32> {
+        match self.levels() {
+            Some((now, full, _)) if full > 0.0 => Some((now / full).clamp(0.0, 1.0)),
+            _ => self.number("capacity").map(|c| (c / 100.0).clamp(0.0, 1.0)),
+        }
+    }
+
+    fn time_to_full(&self, state: battery::State) -> Option<Time> {
+        if state != battery::State::Charging {
+            return None;
+        }
+        match self.levels()? {
+            (now, full, Some(rate)) if rate.abs() > 0.0 && full > now => {
+                Some(Time::new::<hour>((full - now) / rate.abs()))
+            }
+            _ => None,
+        }
+    }
+
+    fn time_to_empty(&self, state: battery::State) -> Option<Time> {
+        if state != battery::State::Discharging {
+            return None;
+        }
+        match self.levels()? {
+            (now, _, Some(rate)) if rate.abs() > 0.0 => Some(Time::new::<hour>(now / rate.abs())),
+            _ => None,
+        }
+    }
+
+    /// Energy attribute in µWh, derived from the matching charge attribute
+    /// (µAh) and `voltage_now` (µV) when the driver only reports charge.
+    fn energy(&self, attr: &str) -> Option<Energy> {
+        let value = match self.number(&format!("energy_{}", attr)) {
+            Some(energy) => energy,
+            None => self.number(&format!("charge_{}", attr))? * self.number("voltage_now")? / 1e6,
+        };
+        Some(Energy::new::<microwatt_hour>(value))
+    }
+
+    fn state_of_health(&self) -> Option<f32> {
+        let full = self.energy("full")?;
+        let design = self.energy("full_design")?;
+        (design.value > 0.0).then(|| (full / design).value)
+    }
+
+    fn energy_rate(&self) -> Option<Power> {
+        let value = match self.number("power_now") {
+            Some(power) => power,
+            None => self.number("current_now")? * self.number("voltage_now")? / 1e6,
+        };
+        Some(Power::new::<microwatt>(value.abs()))
+    }
+
+    fn status(&self) -> Result<BatteryStatus, BatteryError> {
+        let state = self.state();
+        Ok(BatteryStatus {
+            id: self.name.clone(),
+            state,
+            time_to_full: self.time_to_full(state),
+            time_to_empty: self.time_to_empty(state),
+            charge: self.charge().ok_or(BatteryError::FailedToGetState)?,
+            energy: self.energy("now"),
+            energy_full: self.energy("full"),
+            energy_full_design: self.energy("full_design"),
+            energy_rate: self.energy_rate(),
+            voltage: self
+                .number("voltage_now")
+                .map(ElectricPotential::new::<microvolt>),
+            // Tenths of a degree Celsius.
+            temperature: self
+                .number("temp")
+                .map(|t| ThermodynamicTemperature::new::<degree_celsius>(t / 10.0)),
+            // Drivers without a counter report 0.
+            cycle_count: self
+                .get("cycle_count")
+                .and_then(|c| c.parse().ok())
+                .filter(|c| *c > 0),
+            state_of_health: self.state_of_health(),
+            estimate_confidence: None,
+        })
+    }
+}
+
+/// Source reading `power_supply` class devices directly from sysfs. The
+/// root directory can point at a fixture tree instead of the real sysfs.
+pub struct SysfsSource {
+    root: PathBuf,
+}
+
+impl SysfsSource {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        SysfsSource { root: root.into() }
+    }
+
+    fn supplies(&self) -> Result<Vec<PowerSupply>, BatteryError> {
+        let mut dirs = fs::read_dir(&self.root)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        dirs.sort();
+        let mut supplies = Vec::new();
+        for dir in dirs {
+            let supply = PowerSupply::read(&dir)?;
+            if supply.is_battery() {
+                supplies.push(supply);
+            } else {
+                debug!("{}: not a battery", supply.name);
+            }
+        }
+        Ok(supplies)
+    }
+}
+
+impl BatterySource for SysfsSource {
+    fn batteries(&mut self) -> Result<Vec<BatteryStatus>, BatteryError> {
+        let supplies = self.supplies()?;
+        if supplies.is_empty() {
+            return Err(BatteryError::FailedToGetState);
+        }
+        supplies.iter().map(PowerSupply::status).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use battery::units::energy::watt_hour;
+    use battery::units::power::watt;
+    use battery::State;
+
+    use super::*;
+
+    /// A temporary `power_supply` tree, removed when dropped.
+    struct Fixture(PathBuf);
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let root = std::env::temp_dir().join(format!(
+                "battery-notifier-sysfs-{}-{}",
+                process::id(),
+                name
+            ));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(&root).unwrap();
+            Fixture(root)
+        }
+
+        /// Adds a device with `uevent` lines and separate attribute files.
+        fn supply(&self, name: &str, uevent: &[&str], attrs: &[(&str, &str)]) -> &Self {
+            let dir = self.0.join(name);
+            fs::create_dir_all(&dir).unwrap();
+            if !uevent.is_empty() {
+                fs::write(dir.join("uevent"), uevent.join("\n") + "\n").unwrap();
+            }
+            for (attr, value) in attrs {
+                fs::write(dir.join(attr), format!("{}\n", value)).unwrap();
+            }
+            self
+        }
+
+        fn batteries(&self) -> Result<Vec<BatteryStatus>, BatteryError> {
+            SysfsSource::new(&self.0).batteries()
+        }
+
+        fn battery(&self) -> BatteryStatus {
+            let mut batteries = self.batteries().unwrap();
+            assert_eq!(batteries.len(), 1, "{:?}", batteries);
+            batteries.remove(0)
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn assert_near(value: f32, expected: f32) {
+        assert!((value - expected).abs() < 1e-3, "{} != {}", value, expected);
+    }
+
+    #[test]
+    fn reads_uevent() {
+        let fixture = Fixture::new("uevent");
+        fixture.supply(
+            "BAT0",
+            &[
+                "POWER_SUPPLY_TYPE=Battery",
+                "POWER_SUPPLY_STATUS=Discharging",
+                "POWER_SUPPLY_ENERGY_NOW=30000000",
+                "POWER_SUPPLY_ENERGY_FULL=50000000",
+                "POWER_SUPPLY_POWER_NOW=10000000",
+            ],
+            &[],
+        );
+        let status = fixture.battery();
+        assert_eq!(status.id, "BAT0");
+        assert_eq!(status.state, State::Discharging);
+        assert_near(status.charge, 0.6);
+        assert_near(status.energy.unwrap().get::<watt_hour>(), 30.0);
+        assert_near(status.energy_rate.unwrap().get::<watt>(), 10.0);
+        assert_near(status.time_to_empty.unwrap().get::<hour>(), 3.0);
+    }
+
+    #[test]
+    fn reads_attribute_files() {
+        let fixture = Fixture::new("attributes");
+        fixture.supply(
+            "BAT0",
+            &[],
+            &[
+                ("type", "Battery"),
+                ("status", "Charging"),
+                ("energy_now", "30000000"),
+                ("energy_full", "50000000"),
+                ("power_now", "10000000"),
+            ],
+        );
+        let status = fixture.battery();
+        assert_eq!(status.state, State::Charging);
+        assert_near(status.charge, 0.6);
+        assert_near(status.time_to_full.unwrap().get::<hour>(), 2.0);
+    }
+
+    #[test]
+    fn prefers_uevent_over_attribute_files() {
+        let fixture = Fixture::new("both");
+        fixture.supply(
+            "BAT0",
+            &[
+                "POWER_SUPPLY_TYPE=Battery",
+                "POWER_SUPPLY_ENERGY_NOW=30000000",
+            ],
+            &[
+                ("status", "Discharging"),
+                ("energy_now", "10000000"),
+                ("energy_full", "50000000"),
+            ],
+        );
+        let status = fixture.battery();
+        assert_eq!(status.state, State::Discharging);
+        assert_near(status.charge, 0.6);
+    }
+
+    #[test]
+    fn converts_charge_with_voltage() {
+        let fixture = Fixture::new("charge");
+        fixture.supply(
+            "BAT0",
+            &[
+                "POWER_SUPPLY_TYPE=Battery",
+                "POWER_SUPPLY_STATUS=Discharging",
+                "POWER_SUPPLY_CHARGE_NOW=2000000",
+                "POWER_SUPPLY_CHARGE_FULL=4000000",
+                "POWER_SUPPLY_CHARGE_FULL_DESIGN=5000000",
+                "POWER_SUPPLY_CURRENT_NOW=1000000",
+                "POWER_SUPPLY_VOLTAGE_NOW=12000000",
+            ],
+            &[],
+        );
+        let status = fixture.battery();
+        assert_near(status.charge, 0.5);
+        assert_near(status.energy.unwrap().get::<watt_hour>(), 24.0);
+        assert_near(status.energy_full.unwrap().get::<watt_hour>(), 48.0);
+        assert_near(status.energy_rate.unwrap().get::<watt>(), 12.0);
+        assert_near(status.state_of_health.unwrap(), 0.8);
+        assert_near(status.time_to_empty.unwrap().get::<hour>(), 2.0);
+    }
+
+    #[test]
+    fn not_charging_is_full() {
+        let fixture = Fixture::new("not-charging");
+        fixture.supply(
+            "BAT0",
+            &[
+                "POWER_SUPPLY_TYPE=Battery",
+                "POWER_SUPPLY_STATUS=Not charging",
+                "POWER_SUPPLY_CAPACITY=80",
+            ],
+            &[],
+        );
+        let status = fixture.battery();
+        assert_eq!(status.state, State::Full);
+        assert_near(status.charge, 0.8);
+    }
+
+    #[test]
+    fn skips_devices_and_mains() {
+        let fixture = Fixture::new("scope");
+        fixture
+            .supply("AC", &["POWER_SUPPLY_TYPE=Mains"], &[])
+            .supply(
+                "BAT0",
+                &[
+                    "POWER_SUPPLY_TYPE=Battery",
+                    "POWER_SUPPLY_SCOPE=System",
+                    "POWER_SUPPLY_CAPACITY=80",
+                ],
+                &[],
+            )
+            .supply(
+                "hidpp_battery_0",
+                &[
+                    "POWER_SUPPLY_TYPE=Battery",
+                    "POWER_SUPPLY_SCOPE=Device",
+                    "POWER_SUPPLY_CAPACITY=5",
+                ],
+                &[],
+            );
+        assert_eq!(fixture.battery().id, "BAT0");
+    }
+
+    #[test]
+    fn only_devices_is_no_battery() {
+        let fixture = Fixture::new("devices");
+        fixture.supply(
+            "hidpp_battery_0",
+            &["POWER_SUPPLY_TYPE=Battery", "POWER_SUPPLY_CAPACITY=5"],
+            &[("scope", "Device")],
+        );
+        assert!(fixture.batteries().is_err());
+    }
+
+    #[test]
+    fn reads_temperature_in_tenths() {
+        let fixture = Fixture::new("temp");
+        fixture.supply(
+            "BAT0",
+            &[
+                "POWER_SUPPLY_TYPE=Battery",
+                "POWER_SUPPLY_CAPACITY=50",
+                "POWER_SUPPLY_TEMP=312",
+                "POWER_SUPPLY_CYCLE_COUNT=0",
+            ],
+            &[],
+        );
+        let status = fixture.battery();
+        assert_near(status.temperature.unwrap().get::<degree_celsius>(), 31.2);
+        // Drivers without a counter report 0.
+        assert_eq!(status.cycle_count, None);
+    }
+}
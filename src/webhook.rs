@@ -0,0 +1,224 @@
+use std::error::Error;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, error, warn};
+
+use crate::notifier::{Alert, Notifier};
+use crate::payload::AlertPayload;
+
+/// Delay before the first retry, doubled for each one after it.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// How long exiting waits for queued alerts to go out.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// POSTs every alert as JSON to a list of URLs. Requests are made from a
+/// background thread so that slow endpoints and retries never hold up the
+/// main loop.
+pub struct WebhookNotifier {
+    queue: Option<Sender<String>>,
+    /// Disconnected once the worker is done.
+    done: Receiver<()>,
+}
+
+struct Worker {
+    agent: ureq::Agent,
+    urls: Vec<String>,
+    retries: u32,
+    retry_delay: Duration,
+}
+
+impl Worker {
+    fn post(&self, url: &str, body: &str) -> Result<(), ureq::Error> {
+        self.agent
+            .post(url)
+            .header("Content-Type", "application/json")
+            .send(body)?;
+        Ok(())
+    }
+
+    /// Tries each URL up to `retries + 1` times, doubling the delay between
+    /// attempts.
+    fn deliver(&self, body: &str) {
+        for url in &self.urls {
+            let mut delay = self.retry_delay;
+            for attempt in 0..=self.retries {
+                match self.post(url, body) {
+                    Ok(()) => {
+                        debug!("posted alert to {}", url);
+                        break;
+                    }
+                    Err(e) if attempt < self.retries => {
+                        warn!("webhook {} failed, retrying in {:?}: {}", url, delay, e);
+                        thread::sleep(delay);
+                        delay *= 2;
+                    }
+                    Err(e) => error!(
+                        "webhook {} failed after {} attempts: {}",
+                        url,
+                        attempt + 1,
+                        e
+                    ),
+                }
+            }
+        }
+    }
+}
+
+impl WebhookNotifier {
+    pub fn new(urls: Vec<String>, timeout: Duration, retries: u32) -> Self {
+        let agent = ureq::Agent::config_builder()
+            .timeout_global(Some(timeout))
+            .build()
+            .into();
+        let worker = Worker {
+            agent,
+            urls,
+            retries,
+            retry_delay: RETRY_DELAY,
+        };
+        let (queue, rx) = mpsc::channel::<String>();
+        let (done_tx, done) = mpsc::channel();
+        thread::spawn(move || {
+            let _done = done_tx;
+            for body in rx {
+                worker.deliver(&body);
+            }
+        });
+        WebhookNotifier {
+            queue: Some(queue),
+            done,
+        }
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify(&mut self, alert: &Alert) -> Result<(), Box<dyn Error>> {
+        let body = serde_json::to_string(&AlertPayload::from(alert))?;
+        let queue = self.queue.as_ref().ok_or("webhook worker stopped")?;
+        queue.send(body).map_err(|_| "webhook worker stopped")?;
+        Ok(())
+    }
+}
+
+impl Drop for WebhookNotifier {
+    /// Waits for queued alerts to be delivered, or to run out of retries,
+    /// giving up after a short while so that exiting is never held up.
+    fn drop(&mut self) {
+        self.queue.take();
+        if let Err(mpsc::RecvTimeoutError::Timeout) = self.done.recv_timeout(SHUTDOWN_TIMEOUT) {
+            warn!("gave up delivering queued webhook alerts");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    use battery::State;
+
+    use super::*;
+    use crate::config::Urgency;
+    use crate::notifier::AlertKind;
+    use crate::source::BatteryStatus;
+
+    /// A stand-in endpoint answering each request with the next of
+    /// `statuses`, and passing every request body on.
+    fn serve(statuses: Vec<u16>) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/battery", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end().to_ascii_lowercase();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                // Passed on before answering, so that it is there once the
+                // request returns.
+                tx.send(String::from_utf8(body).unwrap()).unwrap();
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    fn alert() -> Alert {
+        Alert {
+            kind: AlertKind::Threshold("low".to_string()),
+            summary: "Battery charge is low".to_string(),
+            body: "charge - 15%".to_string(),
+            urgency: Urgency::Critical,
+            status: BatteryStatus::new("system".to_string(), State::Discharging, 0.15),
+            cancel: None,
+        }
+    }
+
+    #[test]
+    fn posts_alert_as_json() {
+        let (url, bodies) = serve(vec![200]);
+        let mut notifier = WebhookNotifier::new(vec![url], Duration::from_secs(5), 0);
+        notifier.notify(&alert()).unwrap();
+        drop(notifier);
+        let body = bodies.recv_timeout(Duration::from_secs(5)).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["kind"], "threshold");
+        assert_eq!(json["threshold"], "low");
+        assert_eq!(json["urgency"], "critical");
+        assert_eq!(json["summary"], "Battery charge is low");
+        assert_eq!(json["body"], "charge - 15%");
+        assert_eq!(json["battery"]["id"], "system");
+        assert_eq!(json["battery"]["state"], "discharging");
+        assert!((json["battery"]["charge"].as_f64().unwrap() - 0.15).abs() < 1e-6);
+    }
+
+    fn worker(url: String, retries: u32) -> Worker {
+        Worker {
+            agent: ureq::Agent::config_builder()
+                .timeout_global(Some(Duration::from_secs(5)))
+                .build()
+                .into(),
+            urls: vec![url],
+            retries,
+            retry_delay: Duration::from_millis(10),
+        }
+    }
+
+    #[test]
+    fn retries_failed_requests() {
+        let (url, bodies) = serve(vec![500, 503, 200, 200]);
+        worker(url, 3).deliver("{}");
+        // Stops retrying once a request succeeds.
+        assert_eq!(bodies.try_iter().count(), 3);
+    }
+
+    #[test]
+    fn gives_up_after_retries() {
+        let (url, bodies) = serve(vec![500, 500, 500]);
+        let start = Instant::now();
+        worker(url, 2).deliver("{}");
+        assert_eq!(bodies.try_iter().count(), 3);
+        // Waited 10ms, then 20ms.
+        assert!(start.elapsed() >= Duration::from_millis(30));
+    }
+}